@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS mark_job (
+  id uuid PRIMARY KEY,
+  status text NOT NULL,
+  pages_done integer NOT NULL,
+  pages_total integer,
+  error text,
+  result bytea,
+  created_at timestamptz NOT NULL,
+  updated_at timestamptz NOT NULL,
+  expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS mark_job_expires_at ON mark_job (expires_at);
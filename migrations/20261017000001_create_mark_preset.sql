@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS mark_preset (
+  name text PRIMARY KEY,
+  description text NOT NULL,
+  parameters text NOT NULL,
+  created_at timestamptz NOT NULL,
+  updated_at timestamptz NOT NULL
+);
@@ -0,0 +1,268 @@
+use super::mark_pdf::{mark_pdf, run_blocking, MarkContext, MarkParts, MarkQuery, MarkUpload};
+use super::spill::TempFile;
+use super::template::TextTemplate;
+use super::zip::{checksum, ZipWriter};
+use crate::{AppError, AppResult, AppState, DomainError};
+use axum::async_trait;
+use axum::body::{Body, Bytes};
+use axum::extract::{FromRequest, Request, State};
+use axum::http::header::{HeaderName, CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::response::{AppendHeaders, IntoResponse, Response};
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, StreamExt};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+use ulid::Ulid;
+use utoipa::ToSchema;
+
+/// The upload of `/utils/mark/batch`: either several `pdf` parts, or a single `pdf` part with
+/// several `recipient` parts, each `recipient` being the `{user}` of one output.
+pub struct MarkBatchUpload {
+  /// File names and contents.
+  pdfs: Vec<(Option<String>, Arc<TempFile>)>,
+  image: Option<Bytes>,
+  password: Option<String>,
+  recipients: Vec<String>,
+}
+
+/// Multipart form accepted by `/utils/mark/batch`, only used for the OpenAPI document.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+struct MarkBatchForm {
+  /// The documents to watermark, repeated for every document.
+  #[schema(value_type = Vec<String>, format = Binary)]
+  pdf: Vec<Vec<u8>>,
+  /// A PNG or JPEG image drawn instead of the text.
+  #[schema(value_type = Option<String>, format = Binary)]
+  image: Option<Vec<u8>>,
+  /// Password of the encrypted documents.
+  password: Option<String>,
+  /// Value of `{user}` in the text, repeated for every recipient. Requires a single `pdf` and a
+  /// text using `{user}`.
+  recipient: Vec<String>,
+}
+
+#[async_trait]
+impl FromRequest<AppState> for MarkBatchUpload {
+  type Rejection = Response;
+
+  async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+    state
+      .mark_pdf_queue
+      .check_capacity()
+      .map_err(IntoResponse::into_response)?;
+    let MarkParts {
+      pdfs,
+      image,
+      password,
+      recipients,
+    } = MarkParts::read(req, state).await?;
+    let upload = Self {
+      pdfs,
+      image,
+      password,
+      recipients,
+    };
+
+    let invalid = |name| AppError::from(DomainError::PdfInvalidParameter { name }).into_response();
+    if upload.pdfs.is_empty() {
+      return Err(invalid("pdf"));
+    }
+    if !upload.recipients.is_empty() && upload.pdfs.len() > 1 {
+      return Err(invalid("recipient"));
+    }
+    Ok(upload)
+  }
+}
+
+/// Turns `name` into a file name stem that is safe on every platform.
+fn sanitize_file_name(name: &str) -> String {
+  let name = name.strip_suffix(".pdf").unwrap_or(name);
+  let name = name
+    .chars()
+    .map(|c| {
+      if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+        c
+      } else {
+        '_'
+      }
+    })
+    .take(64)
+    .collect::<String>();
+  match name.trim_matches('.') {
+    "" => "document".to_owned(),
+    name => name.to_owned(),
+  }
+}
+
+/// A marked document of the batch, with the CRC-32 and size its ZIP entry header needs.
+struct Entry {
+  name: String,
+  output: TempFile,
+  crc: u32,
+  size: u64,
+}
+
+/// Sends the archive of the batch, each entry as soon as its document has been marked.
+async fn write_archive(
+  sender: &mut mpsc::Sender<std::io::Result<Bytes>>,
+  first: Entry,
+  mut rest: impl Stream<Item = AppResult<Entry>> + Unpin,
+) -> AppResult<()> {
+  let mut zip = ZipWriter::default();
+  let mut entry = first;
+  loop {
+    sender.send(Ok(zip.entry(&entry.name, entry.crc, entry.size)?)).await?;
+    let (_, chunks) = entry.output.into_stream().await?;
+    let mut chunks = std::pin::pin!(chunks);
+    while let Some(chunk) = chunks.next().await {
+      sender.send(Ok(chunk?)).await?;
+    }
+    match rest.next().await {
+      Some(next) => entry = next?,
+      None => break,
+    }
+  }
+  sender.send(Ok(zip.finish()?)).await?;
+  Ok(())
+}
+
+#[utoipa::path(
+  post, path = "/utils/mark/batch",
+  params(MarkQuery),
+  request_body(content((inline(MarkBatchForm) = "multipart/form-data"))),
+  responses((status = 200, body = Vec<u8>, content_type = "application/zip")),
+)]
+pub async fn mark_batch(
+  State(state): State<AppState>,
+  query: MarkQuery,
+  upload: MarkBatchUpload,
+) -> AppResult<impl IntoResponse> {
+  let request_id = Ulid::new();
+  info!(
+    "batch request {} received with {} documents and {} recipients",
+    request_id,
+    upload.pdfs.len(),
+    upload.recipients.len()
+  );
+  query.validate(upload.image.is_some())?;
+  // with an image, or without `{user}` in the text, every recipient would get the same document
+  if !upload.recipients.is_empty()
+    && (upload.image.is_some() || !TextTemplate::parse(&query.text).is_ok_and(|text| text.uses_user()))
+  {
+    return Err(DomainError::PdfInvalidParameter { name: "recipient" }.into());
+  }
+
+  let context = MarkContext::new(&state, &query, request_id)?;
+  let query = Arc::new(query);
+
+  // output file names (numbered, so they are unique and keep the upload order) and inputs
+  let jobs = if upload.recipients.is_empty() {
+    upload
+      .pdfs
+      .into_iter()
+      .enumerate()
+      .map(|(i, (file_name, pdf))| {
+        let name = sanitize_file_name(file_name.as_deref().unwrap_or_default());
+        (format!("{:03}-{}.pdf", i + 1, name), pdf, context.clone())
+      })
+      .collect::<Vec<_>>()
+  } else {
+    let pdf = upload.pdfs[0].1.clone();
+    upload
+      .recipients
+      .into_iter()
+      .enumerate()
+      .map(|(i, recipient)| {
+        let name = format!("{:03}-{}.pdf", i + 1, sanitize_file_name(&recipient));
+        let mut context = context.clone();
+        context.vars.user = recipient;
+        (name, pdf.clone(), context)
+      })
+      .collect()
+  };
+
+  // the batch is admitted through the queue once, and marks its documents in parallel in that slot
+  // and in the ones free right now, up to `mark_pdf_batch_max_jobs`
+  let settings = &state.settings.utils;
+  let mut permit = state.mark_pdf_queue.acquire().await?;
+  let mut slots = 1;
+  while slots < settings.mark_pdf_batch_max_jobs.min(jobs.len()) {
+    let Some(free) = state.mark_pdf_queue.try_acquire() else {
+      break;
+    };
+    permit.merge(free);
+    slots += 1;
+  }
+  let permit = Arc::new(permit);
+  // the whole batch is marked within the time of a single upload
+  let deadline = Instant::now() + Duration::from_secs(settings.mark_pdf_timeout_secs);
+  let cancelled = context.cancelled.clone();
+  let (image, password) = (upload.image, upload.password);
+  let mark = move |(name, pdf, context): (String, Arc<TempFile>, MarkContext)| {
+    let upload = MarkUpload {
+      pdf,
+      image: image.clone(),
+      password: password.clone(),
+    };
+    let (query, permit, cancelled) = (query.clone(), permit.clone(), context.cancelled.clone());
+    async move {
+      let timeout = deadline.saturating_duration_since(Instant::now());
+      let (output, crc, size) = run_blocking(permit, timeout, Some(&cancelled), move || {
+        let output = mark_pdf(&upload, &query, &context)?;
+        let (crc, size) = checksum(std::fs::File::open(output.path())?)?;
+        Ok((output, crc, size))
+      })
+      .await?;
+      AppResult::Ok(Entry {
+        name,
+        output,
+        crc,
+        size,
+      })
+    }
+  };
+  // in upload order, while up to `slots` documents are marked at once
+  let mut entries = futures::stream::iter(jobs.into_iter().map(mark)).buffered(slots);
+
+  // the first document is marked before answering, so that problems common to the whole batch,
+  // like a wrong password, are reported as such instead of as a broken download
+  let first = match entries.next().await {
+    Some(Ok(first)) => first,
+    Some(Err(err)) => {
+      // all documents share the flag, so this stops the ones still running
+      cancelled.store(true, Ordering::Relaxed);
+      return Err(err);
+    }
+    None => return Err(DomainError::PdfInvalidParameter { name: "pdf" }.into()),
+  };
+  let (mut sender, chunks) = mpsc::channel(1);
+  tokio::spawn(async move {
+    if let Err(err) = write_archive(&mut sender, first, entries).await {
+      cancelled.store(true, Ordering::Relaxed);
+      warn!(
+        "batch request {} failed after the response started: {:?}",
+        request_id, err
+      );
+      // abort the response, so that clients do not take the archive for complete
+      let _ = sender
+        .send(Err(std::io::Error::other("the batch could not be completed")))
+        .await;
+    }
+  });
+
+  Ok((
+    AppendHeaders([
+      (CONTENT_TYPE, "application/zip".to_owned()),
+      (
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.zip\"", request_id),
+      ),
+      (HeaderName::from_static("x-request-id"), request_id.to_string()),
+    ]),
+    Body::from_stream(chunks),
+  ))
+}
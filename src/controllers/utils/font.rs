@@ -0,0 +1,176 @@
+use anyhow::Context;
+use lopdf::{dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use ttf_parser::{name_id, Face, GlyphId};
+
+/// A TrueType/OpenType font embedded into watermarked documents as a Type0 font, so that text
+/// outside of WinAnsi (e.g. CJK) renders correctly.
+///
+/// Text is encoded with `Identity-H`, i.e. every character is written as its two byte glyph id.
+#[derive(Debug)]
+pub struct EmbeddedFont {
+  data: Vec<u8>,
+  index: u32,
+}
+
+impl EmbeddedFont {
+  /// Loads the font at `path`. `index` selects the face in a font collection (`.ttc`/`.otc`).
+  pub fn load(path: impl AsRef<Path>, index: u32) -> anyhow::Result<Self> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).with_context(|| format!("failed to read font {}", path.display()))?;
+    Face::parse(&data, index).with_context(|| format!("failed to parse font {}", path.display()))?;
+    Ok(Self { data, index })
+  }
+
+  fn face(&self) -> Face<'_> {
+    Face::parse(&self.data, self.index).expect("font is validated on load")
+  }
+
+  /// Glyphs used by `text`, characters missing from the font are drawn as `.notdef`.
+  fn glyphs<'a>(face: &'a Face<'a>, text: &'a str) -> impl Iterator<Item = (char, GlyphId)> + 'a {
+    text.chars().map(|c| (c, face.glyph_index(c).unwrap_or(GlyphId(0))))
+  }
+
+  /// Width of `text` in points.
+  pub fn width(&self, text: &str, font_size: f32) -> f32 {
+    let face = self.face();
+    let scale = font_size / face.units_per_em() as f32;
+    Self::glyphs(&face, text)
+      .map(|(_, g)| face.glyph_hor_advance(g).unwrap_or(0) as f32 * scale)
+      .sum()
+  }
+
+  /// Encodes `text` as an operand for `Tj`.
+  pub fn encode(&self, text: &str) -> Object {
+    let face = self.face();
+    let bytes = Self::glyphs(&face, text).flat_map(|(_, g)| g.0.to_be_bytes()).collect();
+    Object::String(bytes, StringFormat::Hexadecimal)
+  }
+
+  /// Adds the font to `doc`, subsetted to the glyphs needed by `texts`, and returns the id of the
+  /// Type0 font dictionary.
+  pub fn embed<'a>(&self, doc: &mut Document, texts: impl IntoIterator<Item = &'a str>) -> anyhow::Result<ObjectId> {
+    let face = self.face();
+    let scale = 1000.0 / face.units_per_em() as f32;
+
+    let mut used = BTreeMap::new();
+    for text in texts {
+      for (c, g) in Self::glyphs(&face, text) {
+        used.entry(g.0).or_insert(c);
+      }
+    }
+    used.entry(0).or_insert('\u{FFFD}');
+
+    let glyph_ids = used.keys().copied().collect::<Vec<_>>();
+    let subset = subsetter::subset(&self.data, self.index, subsetter::Profile::pdf(&glyph_ids))
+      .map_err(|e| anyhow::anyhow!("failed to subset font: {}", e))?;
+
+    // the subset tag only has to be unique per document, derive it from the glyph set
+    let hash = glyph_ids.iter().fold(5381u32, |h, &g| h.wrapping_mul(33) ^ g as u32);
+    let tag = (0..6)
+      .map(|i| (b'A' + ((hash >> (i * 5)) % 26) as u8) as char)
+      .collect::<String>();
+    let postscript_name = face
+      .names()
+      .into_iter()
+      .filter(|n| n.name_id == name_id::POST_SCRIPT_NAME)
+      .find_map(|n| n.to_string())
+      .unwrap_or_else(|| "Watermark".to_owned());
+    let base_font = format!("{}+{}", tag, postscript_name.replace(' ', ""));
+
+    let is_cff = face.tables().cff.is_some();
+    let mut font_file = if is_cff {
+      Stream::new(dictionary! { "Subtype" => "OpenType" }, subset)
+    } else {
+      Stream::new(dictionary! { "Length1" => subset.len() as i64 }, subset)
+    };
+    font_file.compress()?;
+    let font_file_id = doc.add_object(font_file);
+
+    let bbox = face.global_bounding_box();
+    let font_descriptor_id = doc.add_object(dictionary! {
+      "Type" => "FontDescriptor",
+      "FontName" => Object::Name(base_font.clone().into_bytes()),
+      "Flags" => 4,
+      "FontBBox" => vec![
+        (bbox.x_min as f32 * scale).into(),
+        (bbox.y_min as f32 * scale).into(),
+        (bbox.x_max as f32 * scale).into(),
+        (bbox.y_max as f32 * scale).into(),
+      ],
+      "ItalicAngle" => face.italic_angle(),
+      "Ascent" => face.ascender() as f32 * scale,
+      "Descent" => face.descender() as f32 * scale,
+      "CapHeight" => face.capital_height().unwrap_or(face.ascender()) as f32 * scale,
+      "StemV" => 80,
+      if is_cff { "FontFile3" } else { "FontFile2" } => font_file_id,
+    });
+
+    let widths = used
+      .keys()
+      .flat_map(|&g| {
+        let width = face.glyph_hor_advance(GlyphId(g)).unwrap_or(0) as f32 * scale;
+        [Object::Integer(g as i64), Object::Array(vec![width.into()])]
+      })
+      .collect::<Vec<_>>();
+    let mut cid_font = dictionary! {
+      "Type" => "Font",
+      "Subtype" => if is_cff { "CIDFontType0" } else { "CIDFontType2" },
+      "BaseFont" => Object::Name(base_font.clone().into_bytes()),
+      "CIDSystemInfo" => dictionary! {
+        "Registry" => Object::string_literal("Adobe"),
+        "Ordering" => Object::string_literal("Identity"),
+        "Supplement" => 0,
+      },
+      "FontDescriptor" => font_descriptor_id,
+      "W" => widths,
+    };
+    if !is_cff {
+      cid_font.set("CIDToGIDMap", "Identity");
+    }
+    let cid_font_id = doc.add_object(cid_font);
+
+    let mut to_unicode = Stream::new(dictionary! {}, to_unicode_cmap(&used).into_bytes());
+    to_unicode.compress()?;
+    let to_unicode_id = doc.add_object(to_unicode);
+
+    Ok(doc.add_object(dictionary! {
+      "Type" => "Font",
+      "Subtype" => "Type0",
+      "BaseFont" => Object::Name(base_font.into_bytes()),
+      "Encoding" => "Identity-H",
+      "DescendantFonts" => vec![cid_font_id.into()],
+      "ToUnicode" => to_unicode_id,
+    }))
+  }
+}
+
+/// Maps glyph ids back to text so the watermark can be searched and copied.
+fn to_unicode_cmap(used: &BTreeMap<u16, char>) -> String {
+  let mut cmap = String::from(
+    "/CIDInit /ProcSet findresource begin\n\
+     12 dict begin\n\
+     begincmap\n\
+     /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+     /CMapName /Adobe-Identity-UCS def\n\
+     /CMapType 2 def\n\
+     1 begincodespacerange\n<0000> <FFFF>\nendcodespacerange\n",
+  );
+  let entries = used.iter().filter(|(&g, _)| g != 0).collect::<Vec<_>>();
+  // a bfchar block holds at most 100 entries
+  for chunk in entries.chunks(100) {
+    let _ = writeln!(cmap, "{} beginbfchar", chunk.len());
+    for (g, c) in chunk {
+      let unicode = c.encode_utf16(&mut [0; 2]).iter().fold(String::new(), |mut s, u| {
+        let _ = write!(s, "{:04X}", u);
+        s
+      });
+      let _ = writeln!(cmap, "<{:04X}> <{}>", g, unicode);
+    }
+    cmap.push_str("endbfchar\n");
+  }
+  cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+  cmap
+}
@@ -0,0 +1,270 @@
+use super::mark_pdf::{add_page_resource, load_document, run_blocking, wrap_page_content, MarkUpload};
+use crate::{AppResult, AppState};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use lopdf::content::Content;
+use lopdf::{dictionary, Document, Object, ObjectId, Stream};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Key of the trace id in the Info dictionary and in every page dictionary.
+const TRACE_KEY: &str = "VATPRCTraceId";
+/// Prefix of the invisible text run carrying the trace id.
+const TRACE_TEXT_PREFIX: &str = "VATPRC-TRACE:";
+const XMP_NAMESPACE: &str = "https://vatprc.net/ns/mark/1.0/";
+const XMP_OPEN_TAG: &str = "<vatprc:TraceId>";
+const XMP_CLOSE_TAG: &str = "</vatprc:TraceId>";
+
+fn xmp_description_start() -> String {
+  format!(r#"<rdf:Description rdf:about="" xmlns:vatprc="{}">"#, XMP_NAMESPACE)
+}
+
+/// Whether `trace_id` can be embedded verbatim into PDF literal strings and XMP.
+pub fn is_valid_trace_id(trace_id: &str) -> bool {
+  (1..=64).contains(&trace_id.len())
+    && trace_id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':' | '@'))
+}
+
+/// Embeds `trace_id` invisibly into `doc`: in the Info dictionary, the XMP metadata, a private key
+/// of every page and an invisible text run at the start of every page. Each copy survives on its
+/// own, so stripping some of them still leaves the document traceable.
+pub fn embed_trace(doc: &mut Document, page_ids: &[ObjectId], trace_id: &str) -> lopdf::Result<()> {
+  // Info dictionary
+  let info = Object::string_literal(trace_id);
+  match doc.trailer.get(b"Info") {
+    Ok(Object::Reference(info_id)) => {
+      let info_id = *info_id;
+      doc.get_dictionary_mut(info_id)?.set(TRACE_KEY, info);
+    }
+    Ok(_) => doc.trailer.get_mut(b"Info")?.as_dict_mut()?.set(TRACE_KEY, info),
+    Err(_) => {
+      let info_id = doc.add_object(dictionary! { TRACE_KEY => info });
+      doc.trailer.set("Info", info_id);
+    }
+  }
+
+  // XMP metadata, kept uncompressed so it stays readable to metadata tools
+  let description = format!(
+    "{}{}{}{}</rdf:Description>",
+    xmp_description_start(),
+    XMP_OPEN_TAG,
+    trace_id,
+    XMP_CLOSE_TAG
+  );
+  let metadata_id = doc.catalog()?.get(b"Metadata").and_then(Object::as_reference).ok();
+  let existing = metadata_id
+    .and_then(|id| doc.get_object(id).and_then(Object::as_stream).ok())
+    .and_then(|stream| stream.get_plain_content().ok())
+    .and_then(|xmp| String::from_utf8(xmp).ok())
+    .filter(|xmp| xmp.contains("</rdf:RDF>"));
+  let xmp = match existing {
+    Some(xmp) => xmp.replacen("</rdf:RDF>", &format!("{}</rdf:RDF>", description), 1),
+    None => format!(
+      "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+       <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+       <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">{}</rdf:RDF>\
+       </x:xmpmeta>\n<?xpacket end=\"w\"?>",
+      description
+    ),
+  };
+  let metadata = Stream::new(
+    dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+    xmp.into_bytes(),
+  );
+  match metadata_id {
+    Some(id) => *doc.get_object_mut(id)? = metadata.into(),
+    None => {
+      let metadata_id = doc.add_object(metadata);
+      doc.catalog_mut()?.set("Metadata", metadata_id);
+    }
+  }
+
+  // private page keys and invisible text runs
+  let font_id = doc.add_object(dictionary! {
+    "Type" => "Font",
+    "Subtype" => "Type1",
+    "BaseFont" => "Helvetica",
+  });
+  let text = format!(
+    "q BT /FT_VATPRC 1 Tf 3 Tr 0 0 Td ({}{}) Tj ET Q\n",
+    TRACE_TEXT_PREFIX, trace_id
+  );
+  for &page_id in page_ids {
+    add_page_resource(doc, page_id, "Font", "FT_VATPRC", font_id)?;
+    doc
+      .get_dictionary_mut(page_id)?
+      .set(TRACE_KEY, Object::string_literal(trace_id));
+
+    // drawn first, so it starts from the default graphics state
+    wrap_page_content(doc, page_id, text.clone().into_bytes(), Vec::new())?;
+  }
+  Ok(())
+}
+
+/// Removes the trace ids embedded by [`embed_trace`] from the metadata and page dictionaries. The
+/// invisible text runs are removed along with the visible watermark.
+pub fn strip_trace(doc: &mut Document, page_ids: &[ObjectId]) -> lopdf::Result<()> {
+  let info_id = match doc.trailer.get_mut(b"Info") {
+    Ok(Object::Reference(info_id)) => Some(*info_id),
+    Ok(Object::Dictionary(info)) => {
+      info.remove(TRACE_KEY.as_bytes());
+      None
+    }
+    _ => None,
+  };
+  if let Some(info) = info_id.and_then(|id| doc.get_dictionary_mut(id).ok()) {
+    info.remove(TRACE_KEY.as_bytes());
+  }
+
+  let metadata_id = doc.catalog()?.get(b"Metadata").and_then(Object::as_reference).ok();
+  if let Some(metadata) = metadata_id.and_then(|id| doc.get_object_mut(id).and_then(Object::as_stream_mut).ok()) {
+    let xmp = metadata.get_plain_content().map(String::from_utf8);
+    if let Ok(Ok(mut xmp)) = xmp {
+      let start = xmp_description_start();
+      while let Some(begin) = xmp.find(&start) {
+        let Some(length) = xmp[begin..].find("</rdf:Description>") else {
+          break;
+        };
+        xmp.replace_range(begin..begin + length + "</rdf:Description>".len(), "");
+      }
+      metadata.set_plain_content(xmp.into_bytes());
+    }
+  }
+
+  for &page_id in page_ids {
+    doc.get_dictionary_mut(page_id)?.remove(TRACE_KEY.as_bytes());
+  }
+  Ok(())
+}
+
+/// Where a trace id was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceLocation {
+  Info,
+  Xmp,
+  PageKey,
+  PageText,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TraceFinding {
+  pub location: TraceLocation,
+  /// 1-based page number for page level locations.
+  pub page: Option<usize>,
+  pub trace_id: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TraceReport {
+  /// Distinct trace ids found in the document.
+  pub trace_ids: Vec<String>,
+  pub findings: Vec<TraceFinding>,
+}
+
+/// Collects every trace id embedded by [`embed_trace`], skipping parts of the document that
+/// cannot be read.
+pub fn find_traces(doc: &Document) -> TraceReport {
+  let mut findings = Vec::new();
+  let string = |object: &Object| {
+    let object = doc.dereference(object).ok()?.1;
+    Some(String::from_utf8_lossy(object.as_str().ok()?).into_owned())
+  };
+
+  let info = doc
+    .trailer
+    .get(b"Info")
+    .ok()
+    .and_then(|info| doc.dereference(info).ok()?.1.as_dict().ok());
+  if let Some(trace_id) = info.and_then(|info| string(info.get(TRACE_KEY.as_bytes()).ok()?)) {
+    findings.push(TraceFinding {
+      location: TraceLocation::Info,
+      page: None,
+      trace_id,
+    });
+  }
+
+  let xmp = doc
+    .catalog()
+    .and_then(|catalog| catalog.get(b"Metadata"))
+    .and_then(|metadata| doc.dereference(metadata))
+    .and_then(|(_, metadata)| metadata.as_stream()?.get_plain_content());
+  if let Ok(xmp) = xmp {
+    let xmp = String::from_utf8_lossy(&xmp);
+    let mut rest = xmp.as_ref();
+    while let Some((_, after)) = rest.split_once(XMP_OPEN_TAG) {
+      let Some((trace_id, after)) = after.split_once(XMP_CLOSE_TAG) else {
+        break;
+      };
+      findings.push(TraceFinding {
+        location: TraceLocation::Xmp,
+        page: None,
+        trace_id: trace_id.trim().to_owned(),
+      });
+      rest = after;
+    }
+  }
+
+  for (i, page_id) in doc.page_iter().enumerate() {
+    let page = Some(i + 1);
+    let dict = doc.get_dictionary(page_id).ok();
+    if let Some(trace_id) = dict.and_then(|dict| string(dict.get(TRACE_KEY.as_bytes()).ok()?)) {
+      findings.push(TraceFinding {
+        location: TraceLocation::PageKey,
+        page,
+        trace_id,
+      });
+    }
+
+    let Ok(content) = doc
+      .get_page_content(page_id)
+      .and_then(|content| Content::decode(&content))
+    else {
+      continue;
+    };
+    for operation in content.operations.iter().filter(|operation| operation.operator == "Tj") {
+      let trace_id = operation
+        .operands
+        .first()
+        .and_then(|operand| operand.as_str().ok())
+        .and_then(|text| text.strip_prefix(TRACE_TEXT_PREFIX.as_bytes()));
+      if let Some(trace_id) = trace_id {
+        findings.push(TraceFinding {
+          location: TraceLocation::PageText,
+          page,
+          trace_id: String::from_utf8_lossy(trace_id).into_owned(),
+        });
+      }
+    }
+  }
+
+  let trace_ids = findings
+    .iter()
+    .map(|finding| finding.trace_id.clone())
+    .collect::<BTreeSet<_>>()
+    .into_iter()
+    .collect();
+  TraceReport { trace_ids, findings }
+}
+
+#[utoipa::path(
+  post, path = "/utils/mark/trace",
+  params(("X-Pdf-Password" = Option<String>, Header, description = "Password of an encrypted document")),
+  request_body(content((Vec<u8> = "application/pdf"))),
+  responses((status = 200, body = TraceReport)),
+)]
+pub async fn find_trace(State(state): State<AppState>, upload: MarkUpload) -> AppResult<impl IntoResponse> {
+  let permit = state.mark_pdf_queue.acquire().await?;
+  let timeout = Duration::from_secs(state.settings.utils.mark_pdf_timeout_secs);
+  let report = run_blocking(Arc::new(permit), timeout, None, move || {
+    load_document(&upload).map(|doc| find_traces(&doc))
+  })
+  .await?;
+  Ok(Json(report))
+}
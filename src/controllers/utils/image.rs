@@ -0,0 +1,79 @@
+use lopdf::{dictionary, Document, ObjectId, Stream};
+
+/// A PNG or JPEG image drawn as the watermark, embedded as an RGB image XObject with its alpha
+/// channel as a soft mask.
+pub struct WatermarkImage {
+  width: u32,
+  height: u32,
+  rgb: Vec<u8>,
+  alpha: Option<Vec<u8>>,
+}
+
+impl WatermarkImage {
+  pub fn decode(data: &[u8]) -> ::image::ImageResult<Self> {
+    let image = ::image::load_from_memory(data)?;
+    let has_alpha = image.color().has_alpha();
+    let rgba = image.into_rgba8();
+    let (width, height) = rgba.dimensions();
+
+    let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+    let mut alpha = Vec::with_capacity((width * height) as usize);
+    for pixel in rgba.pixels() {
+      rgb.extend_from_slice(&pixel.0[..3]);
+      alpha.push(pixel.0[3]);
+    }
+    // skip the soft mask for images that are opaque anyway
+    let alpha = Some(alpha).filter(|alpha| has_alpha && alpha.iter().any(|&a| a != u8::MAX));
+
+    Ok(Self {
+      width,
+      height,
+      rgb,
+      alpha,
+    })
+  }
+
+  /// Width in pixels.
+  pub fn width(&self) -> u32 {
+    self.width
+  }
+
+  /// Height in pixels.
+  pub fn height(&self) -> u32 {
+    self.height
+  }
+
+  /// Adds the image to `doc` and returns the id of the image XObject.
+  pub fn embed(&self, doc: &mut Document) -> lopdf::Result<ObjectId> {
+    let mut image = Stream::new(
+      dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => self.width as i64,
+        "Height" => self.height as i64,
+        "ColorSpace" => "DeviceRGB",
+        "BitsPerComponent" => 8,
+      },
+      self.rgb.clone(),
+    );
+
+    if let Some(alpha) = &self.alpha {
+      let mut soft_mask = Stream::new(
+        dictionary! {
+          "Type" => "XObject",
+          "Subtype" => "Image",
+          "Width" => self.width as i64,
+          "Height" => self.height as i64,
+          "ColorSpace" => "DeviceGray",
+          "BitsPerComponent" => 8,
+        },
+        alpha.clone(),
+      );
+      soft_mask.compress()?;
+      image.dict.set("SMask", doc.add_object(soft_mask));
+    }
+
+    image.compress()?;
+    Ok(doc.add_object(image))
+  }
+}
@@ -0,0 +1,311 @@
+use super::mark_pdf::{
+  decrypt_document, inherited_attribute, open_document, run_blocking, MarkUpload, Permissions, FORM_PREFIX,
+};
+use crate::{AppResult, AppState};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use qpdf::{QPdf, QPdfDictionary, QPdfObjectLike, QPdfObjectType, QPdfScalar};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// The encryption of an inspected document.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EncryptionReport {
+  /// Revision of the security handler (`R`), 6 for AES-256 as written by `/utils/mark`.
+  pub revision: i64,
+  /// Raw permission flags (`P`).
+  pub permission_flags: i64,
+  /// The `permissions` of `/utils/mark` producing this encryption, if any.
+  pub profile: Option<Permissions>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct InspectReport {
+  /// Whether any page carries a watermark of this service.
+  pub marked: bool,
+  pub page_count: usize,
+  /// 1-based numbers of the watermarked pages.
+  pub marked_pages: Vec<usize>,
+  /// Distinct watermark texts in page order, empty for image watermarks.
+  pub texts: Vec<String>,
+  /// Whether the document is encrypted, even if its encryption could not be read.
+  pub encrypted: bool,
+  /// `null` if the document is not encrypted, or if it is but its encryption could not be read.
+  pub encryption: Option<EncryptionReport>,
+}
+
+// permission flags, see ISO 32000-1 table 22
+const PERMISSION_PRINT: i64 = 1 << 2;
+const PERMISSION_PRINT_HIGH_QUALITY: i64 = 1 << 11;
+// without accessibility (bit 10), which PDF 2.0 readers assume and qpdf always sets for R6
+const PERMISSION_OTHERS: i64 = (1 << 3) | (1 << 4) | (1 << 5) | (1 << 8) | (1 << 10);
+
+/// Reads the encryption dictionary of a document as opened by qpdf, which keeps it in the trailer.
+fn encryption_report(doc: &QPdf) -> Option<EncryptionReport> {
+  let encrypt = doc.get_trailer()?.get("/Encrypt")?;
+  if encrypt.get_type() != QPdfObjectType::Dictionary {
+    return None;
+  }
+  let encrypt = QPdfDictionary::from(encrypt);
+  let integer = |key| {
+    let value = encrypt.get(key)?;
+    (value.get_type() == QPdfObjectType::Integer).then(|| QPdfScalar::from(value).as_i64())
+  };
+  let revision = integer("/R")?;
+  let permission_flags = integer("/P")?;
+
+  let profile = match (
+    revision,
+    permission_flags & PERMISSION_PRINT != 0,
+    permission_flags & PERMISSION_PRINT_HIGH_QUALITY != 0,
+  ) {
+    _ if permission_flags & PERMISSION_OTHERS != 0 => None,
+    (6, true, false) => Some(Permissions::Restricted),
+    (6, true, true) => Some(Permissions::Print),
+    _ => None,
+  };
+  Some(EncryptionReport {
+    revision,
+    permission_flags,
+    profile,
+  })
+}
+
+/// Looks up `name` in the `category` resources of a page.
+fn page_resource<'a>(doc: &'a Document, resources: &'a Dictionary, category: &[u8], name: &[u8]) -> Option<&'a Object> {
+  let category = doc.dereference(resources.get(category).ok()?).ok()?.1.as_dict().ok()?;
+  Some(doc.dereference(category.get(name).ok()?).ok()?.1)
+}
+
+/// Maps the glyph ids of an embedded watermark font back to text using the bfchar entries of its
+/// ToUnicode CMap, as written by `EmbeddedFont`.
+fn glyph_map(doc: &Document, font: &Dictionary) -> Option<HashMap<u16, String>> {
+  let to_unicode = doc.dereference(font.get(b"ToUnicode").ok()?).ok()?.1.as_stream().ok()?;
+  let cmap = to_unicode.get_plain_content().ok()?;
+  let cmap = String::from_utf8_lossy(&cmap);
+
+  fn hex(value: &str) -> Option<&str> {
+    value.strip_prefix('<')?.strip_suffix('>')
+  }
+  let mut map = HashMap::new();
+  for line in cmap.lines() {
+    // `<glyph> <utf-16be>` lines of the bfchar blocks
+    let [glyph, unicode] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+      continue;
+    };
+    let (Some(glyph), Some(unicode)) = (hex(glyph), hex(unicode)) else {
+      continue;
+    };
+    let Ok(glyph) = u16::from_str_radix(glyph, 16) else {
+      continue;
+    };
+    let units = (0..unicode.len() / 4)
+      .filter_map(|i| u16::from_str_radix(unicode.get(i * 4..i * 4 + 4)?, 16).ok())
+      .collect::<Vec<_>>();
+    map.insert(glyph, String::from_utf16_lossy(&units));
+  }
+  Some(map)
+}
+
+/// Watermark text lines of a marked page, `None` if the page is not marked.
+fn inspect_page(doc: &Document, page_id: ObjectId) -> Option<Vec<String>> {
+  let resources = inherited_attribute(doc, page_id, b"Resources").ok()??;
+  let resources = resources.as_dict().ok()?;
+  page_resource(doc, resources, b"ExtGState", b"GS_VATPRC")?;
+
+  // resources may be shared with pages that are not marked, so look for the drawing itself
+  let content = Content::decode(&doc.get_page_content(page_id).ok()?).ok()?;
+  let draws_watermark = content.operations.iter().any(|operation| {
+    operation.operator == "gs" && operation.operands.first().and_then(|o| o.as_name().ok()) == Some(b"GS_VATPRC")
+  });
+  if !draws_watermark {
+    return None;
+  }
+
+  let mut lines = Vec::new();
+  watermark_lines(doc, resources, &content.operations, &mut lines);
+  Some(lines)
+}
+
+/// Collects the distinct lines drawn with the watermark font by `operations`, following the forms
+/// drawing the tiles with `reuse_tiles`.
+fn watermark_lines(doc: &Document, resources: &Dictionary, operations: &[Operation], lines: &mut Vec<String>) {
+  let font = page_resource(doc, resources, b"Font", b"F_VATPRC").and_then(|font| font.as_dict().ok());
+  let glyphs = font.and_then(|font| glyph_map(doc, font));
+  let decode = |bytes: &[u8]| match &glyphs {
+    Some(glyphs) => bytes
+      .chunks(2)
+      .map(|g| {
+        let glyph = u16::from_be_bytes([g[0], *g.get(1).unwrap_or(&0)]);
+        glyphs.get(&glyph).map_or("\u{FFFD}", String::as_str).to_owned()
+      })
+      .collect::<String>(),
+    // the standard font is drawn from the text as is
+    None => String::from_utf8_lossy(bytes).into_owned(),
+  };
+
+  let mut in_watermark_font = false;
+  for operation in operations {
+    match operation.operator.as_str() {
+      "Tf" => {
+        in_watermark_font = operation.operands.first().and_then(|o| o.as_name().ok()) == Some(b"F_VATPRC");
+      }
+      "Tj" if in_watermark_font => {
+        let Some(text) = operation.operands.first().and_then(|o| o.as_str().ok()) else {
+          continue;
+        };
+        let line = decode(text);
+        // tiles repeat the same lines
+        if !lines.contains(&line) {
+          lines.push(line);
+        }
+      }
+      "Do" => {
+        let Some(name) = operation.operands.first().and_then(|o| o.as_name().ok()) else {
+          continue;
+        };
+        if !name.starts_with(FORM_PREFIX.as_bytes()) {
+          continue;
+        }
+        let Some(form) = page_resource(doc, resources, b"XObject", name).and_then(|form| form.as_stream().ok()) else {
+          continue;
+        };
+        let form_resources = form
+          .dict
+          .get(b"Resources")
+          .and_then(|resources| doc.dereference(resources))
+          .and_then(|(_, resources)| resources.as_dict());
+        let content = form
+          .get_plain_content()
+          .ok()
+          .and_then(|content| Content::decode(&content).ok());
+        if let (Ok(form_resources), Some(content)) = (form_resources, content) {
+          watermark_lines(doc, form_resources, &content.operations, lines);
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+fn inspect_pdf(upload: &MarkUpload) -> AppResult<InspectReport> {
+  let doc = open_document(upload)?;
+  // the encryption dictionary is gone once decrypted
+  let encrypted = doc.is_encrypted();
+  let encryption = encrypted.then(|| encryption_report(&doc)).flatten();
+  let doc = decrypt_document(upload, &doc)?;
+
+  let mut marked_pages = Vec::new();
+  let mut texts = Vec::<String>::new();
+  let mut page_count = 0;
+  for (i, page_id) in doc.page_iter().enumerate() {
+    page_count += 1;
+    let Some(lines) = inspect_page(&doc, page_id) else {
+      continue;
+    };
+    marked_pages.push(i + 1);
+    let text = lines.join("\n");
+    if !text.is_empty() && !texts.contains(&text) {
+      texts.push(text);
+    }
+  }
+
+  Ok(InspectReport {
+    marked: !marked_pages.is_empty(),
+    page_count,
+    marked_pages,
+    texts,
+    encrypted,
+    encryption,
+  })
+}
+
+#[utoipa::path(
+  post, path = "/utils/mark/inspect",
+  params(("X-Pdf-Password" = Option<String>, Header, description = "Password of an encrypted document")),
+  request_body(content((Vec<u8> = "application/pdf"))),
+  responses((status = 200, body = InspectReport)),
+)]
+pub async fn inspect(State(state): State<AppState>, upload: MarkUpload) -> AppResult<impl IntoResponse> {
+  let permit = state.mark_pdf_queue.acquire().await?;
+  let timeout = Duration::from_secs(state.settings.utils.mark_pdf_timeout_secs);
+  let report = run_blocking(Arc::new(permit), timeout, None, move || inspect_pdf(&upload)).await?;
+  Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lopdf::{dictionary, Stream};
+  use qpdf::{EncryptionParams, EncryptionParamsR6, ObjectStreamMode, PrintPermission};
+
+  fn sample_pdf() -> Vec<u8> {
+    let mut doc = Document::with_version("1.7");
+    let pages_id = doc.new_object_id();
+    let content_id = doc.add_object(Stream::new(dictionary! {}, b"0 0 m 10 10 l S".to_vec()));
+    let page_id = doc.add_object(dictionary! {
+      "Type" => "Page",
+      "Parent" => pages_id,
+      "MediaBox" => vec![0.into(), 0.into(), 100.into(), 100.into()],
+      "Contents" => content_id,
+    });
+    doc.objects.insert(
+      pages_id,
+      Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page_id.into()], "Count" => 1 }),
+    );
+    let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+    doc.trailer.set("Root", catalog_id);
+    let mut pdf = Vec::new();
+    doc.save_to(&mut pdf).unwrap();
+    pdf
+  }
+
+  /// Encrypts like a watermarked output, with the encryption dictionary optionally in an object stream.
+  fn encrypt(pdf: &[u8], allow_print: PrintPermission, object_streams: bool) -> Vec<u8> {
+    let doc = QPdf::read_from_memory(pdf).unwrap();
+    let mut writer = doc.writer();
+    writer.encryption_params(EncryptionParams::R6(EncryptionParamsR6 {
+      user_password: "".to_owned(),
+      owner_password: "owner".to_owned(),
+      allow_accessibility: false,
+      allow_extract: false,
+      allow_assemble: false,
+      allow_annotate_and_form: false,
+      allow_form_filling: false,
+      allow_modify_other: false,
+      allow_print,
+      encrypt_metadata: false,
+    }));
+    if object_streams {
+      writer.object_stream_mode(ObjectStreamMode::Generate);
+    }
+    writer.write_to_memory().unwrap()
+  }
+
+  #[test]
+  fn encryption() {
+    for object_streams in [false, true] {
+      for (allow_print, high_quality) in [(PrintPermission::Low, false), (PrintPermission::Full, true)] {
+        let doc = QPdf::read_from_memory(encrypt(&sample_pdf(), allow_print, object_streams)).unwrap();
+        assert!(doc.is_encrypted());
+        let report = encryption_report(&doc).expect("encryption is readable");
+        assert_eq!(report.revision, 6);
+        match report.profile {
+          Some(Permissions::Restricted) => assert!(!high_quality),
+          Some(Permissions::Print) => assert!(high_quality),
+          profile => panic!("unexpected profile {profile:?}"),
+        }
+      }
+    }
+
+    let doc = QPdf::read_from_memory(sample_pdf()).unwrap();
+    assert!(!doc.is_encrypted());
+    assert!(encryption_report(&doc).is_none());
+  }
+}
@@ -0,0 +1,238 @@
+use super::mark_pdf::{mark_pdf, run_blocking, MarkContext, MarkForm, MarkQuery, MarkUpload};
+use super::queue::Admission;
+use crate::database::AsMarkJobAccessor;
+use crate::{AppError, AppResult, AppState, DomainError};
+use axum::extract::{Path, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION};
+use axum::http::StatusCode;
+use axum::response::{AppendHeaders, IntoResponse};
+use axum::Json;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use ulid::Ulid;
+use utoipa::ToSchema;
+
+/// How often the progress of a running job is written to the database.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Problem type of jobs failed by an unexpected error.
+const INTERNAL_ERROR: &str = "urn:vatprc-uniapi-error:common.internal";
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkJobStatus {
+  /// Waiting for a free slot, see `/utils/mark/status`.
+  Queued,
+  Running,
+  /// The result can be downloaded from `/utils/mark/jobs/{id}/result`.
+  Succeeded,
+  Failed,
+}
+
+impl MarkJobStatus {
+  fn from_database(status: &str) -> Self {
+    match status {
+      "queued" => Self::Queued,
+      "running" => Self::Running,
+      "succeeded" => Self::Succeeded,
+      _ => Self::Failed,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct MarkJob {
+  pub id: Ulid,
+  pub status: MarkJobStatus,
+  /// Number of pages marked so far.
+  pub pages_done: i32,
+  /// Number of pages to mark, `null` until the document has been loaded.
+  pub pages_total: Option<i32>,
+  /// Problem type of a failed job, as it would have been returned by `/utils/mark`.
+  pub error: Option<String>,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+  /// Once the job has finished, the job and its result are deleted after this time.
+  pub expires_at: DateTime<Utc>,
+}
+
+impl From<crate::database::MarkJob> for MarkJob {
+  fn from(job: crate::database::MarkJob) -> Self {
+    Self {
+      id: job.id.into(),
+      status: MarkJobStatus::from_database(&job.status),
+      pages_done: job.pages_done,
+      pages_total: job.pages_total,
+      error: job.error,
+      created_at: job.created_at,
+      updated_at: job.updated_at,
+      expires_at: job.expires_at,
+    }
+  }
+}
+
+fn expires_at(state: &AppState) -> DateTime<Utc> {
+  let ttl = state.settings.utils.mark_pdf_job_ttl_secs;
+  Utc::now() + TimeDelta::seconds(i64::try_from(ttl).unwrap_or(i64::MAX / 1000))
+}
+
+/// Runs `mark_pdf` for a job, reporting its progress until it has finished.
+async fn execute_job(
+  state: &AppState,
+  id: Ulid,
+  admission: Admission,
+  upload: MarkUpload,
+  query: MarkQuery,
+  context: MarkContext,
+) -> AppResult<Vec<u8>> {
+  let permit = admission.wait().await?;
+  let cancelled = context.cancelled.clone();
+  let progress = context.progress.clone();
+  let timeout = Duration::from_secs(state.settings.utils.mark_pdf_job_timeout_secs);
+  let task = run_blocking(Arc::new(permit), timeout, Some(&cancelled), move || {
+    let output = mark_pdf(&upload, &query, &context)?;
+    Ok(std::fs::read(output.path())?)
+  });
+  tokio::pin!(task);
+
+  let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+  let mut reported = None;
+  loop {
+    tokio::select! {
+      result = &mut task => return result,
+      _ = interval.tick() => {
+        let pages_done = progress.pages_done.load(Ordering::Relaxed) as i32;
+        let pages_total = progress.pages_total.load(Ordering::Relaxed) as i32;
+        if reported == Some((pages_done, pages_total)) {
+          continue;
+        }
+        reported = Some((pages_done, pages_total));
+        let pages_total = (pages_total > 0).then_some(pages_total);
+        // progress is informational, keep marking if it cannot be saved
+        if let Err(err) = state.mark_job_accessor().set_progress(id, pages_done, pages_total).await {
+          warn!("failed to save progress of mark job {}: {:?}", id, err);
+        }
+      }
+    }
+  }
+}
+
+async fn run_job(
+  state: AppState,
+  id: Ulid,
+  admission: Admission,
+  upload: MarkUpload,
+  query: MarkQuery,
+  context: MarkContext,
+) {
+  let accessor = state.mark_job_accessor();
+  let saved = match execute_job(&state, id, admission, upload, query, context).await {
+    Ok(output) => {
+      info!("mark job {} succeeded", id);
+      accessor.succeed(id, &output, expires_at(&state)).await
+    }
+    Err(err) => {
+      let code = match &err {
+        AppError::DomainError(err) => err.code(),
+        AppError::Unknown(err) => {
+          error!("mark job {} failed: {:?}", id, err);
+          INTERNAL_ERROR
+        }
+      };
+      accessor.fail(id, code, expires_at(&state)).await
+    }
+  };
+  if let Err(err) = saved {
+    error!("failed to save the result of mark job {}: {:?}", id, err);
+  }
+}
+
+/// Fails the jobs left queued or running by a previous process, which nothing will finish anymore.
+pub async fn fail_interrupted_jobs(state: &AppState) -> AppResult<()> {
+  let interrupted = state
+    .mark_job_accessor()
+    .fail_unfinished(INTERNAL_ERROR, expires_at(state))
+    .await?;
+  if interrupted > 0 {
+    warn!("failed {} mark jobs interrupted by a restart", interrupted);
+  }
+  Ok(())
+}
+
+#[utoipa::path(
+  post, path = "/utils/mark/jobs",
+  params(
+    MarkQuery,
+    ("X-Pdf-Password" = Option<String>, Header, description = "Password of an encrypted document"),
+  ),
+  request_body(content(
+    (Vec<u8> = "application/pdf"),
+    (inline(MarkForm) = "multipart/form-data"),
+  )),
+  responses((status = 202, body = MarkJob)),
+)]
+pub async fn create_job(
+  State(state): State<AppState>,
+  query: MarkQuery,
+  upload: MarkUpload,
+) -> AppResult<impl IntoResponse> {
+  query.validate(upload.image.is_some())?;
+  let id = Ulid::new();
+  let context = MarkContext::new(&state, &query, id)?;
+  // a full queue turns the job away now, accepted jobs wait for their turn in the background
+  let admission = state.mark_pdf_queue.admit()?;
+
+  let accessor = state.mark_job_accessor();
+  // there are few jobs, so expired ones are cleaned up along the way
+  let expired = accessor.delete_expired().await?;
+  if expired > 0 {
+    info!("deleted {} expired mark jobs", expired);
+  }
+  let job = accessor.create(id, expires_at(&state)).await?;
+  info!("mark job {} created", id);
+
+  tokio::spawn(run_job(state.clone(), id, admission, upload, query, context));
+
+  Ok((
+    StatusCode::ACCEPTED,
+    AppendHeaders([(LOCATION, format!("/utils/mark/jobs/{}", id))]),
+    Json(MarkJob::from(job)),
+  ))
+}
+
+#[utoipa::path(
+  get, path = "/utils/mark/jobs/{id}",
+  responses((status = 200, body = MarkJob)),
+  params(("id" = Ulid, Path, description = "The ID of the job")),
+)]
+pub async fn get_job(State(state): State<AppState>, Path(id): Path<Ulid>) -> AppResult<impl IntoResponse> {
+  let Some(job) = state.mark_job_accessor().get(id).await?.map(MarkJob::from) else {
+    return Err(DomainError::MarkJobNotFound { id }.into());
+  };
+  Ok(Json(job))
+}
+
+#[utoipa::path(
+  get, path = "/utils/mark/jobs/{id}/result",
+  responses((status = 200, body = Vec<u8>, content_type = "application/pdf")),
+  params(("id" = Ulid, Path, description = "The ID of the job")),
+)]
+pub async fn get_job_result(State(state): State<AppState>, Path(id): Path<Ulid>) -> AppResult<impl IntoResponse> {
+  let accessor = state.mark_job_accessor();
+  let Some(result) = accessor.get_result(id).await? else {
+    return match accessor.get(id).await? {
+      Some(_) => Err(DomainError::MarkJobNotReady { id }.into()),
+      None => Err(DomainError::MarkJobNotFound { id }.into()),
+    };
+  };
+  Ok((
+    AppendHeaders([
+      (CONTENT_TYPE, "application/pdf".to_owned()),
+      (CONTENT_DISPOSITION, format!("attachment; filename=\"{}.pdf\"", id)),
+    ]),
+    result,
+  ))
+}
@@ -1,16 +1,34 @@
 use self::pdf_points::PdfPoints;
 use self::text_width::helvetica_width;
-use crate::AppResult;
-use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
-use axum::response::{AppendHeaders, IntoResponse};
-use axum::{body::Bytes, extract::Query};
+use super::{
+  embed_trace, is_valid_trace_id, spill_dir, strip_watermark, EmbeddedFont, PageSelection, TempFile, TemplateVars,
+  TextTemplate, WatermarkImage,
+};
+use crate::settings::UtilsSettings;
+use crate::{AppError, AppResult, AppState, DomainError};
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Multipart, Request, State};
+use axum::http::header::{HeaderName, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::response::{AppendHeaders, IntoResponse, Response};
+use chrono::format::{Item, StrftimeItems};
+use chrono::Utc;
+use chrono_tz::Tz;
+use futures::StreamExt;
 use lopdf::content::{Content, Operation};
-use lopdf::Document;
 use lopdf::Object;
-use lopdf::{dictionary, Dictionary};
-use nalgebra::{Isometry2, Matrix3, Point2, Vector2};
-use serde::Deserialize;
-use tracing::info;
+use lopdf::{dictionary, Dictionary, Stream};
+use lopdf::{Document, ObjectId};
+use nalgebra::{Isometry2, Point2, Vector2};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{info, warn};
+use ulid::Ulid;
+use utoipa::{IntoParams, ToSchema};
 
 fn norm_to_rot(theta: f32, page_w: PdfPoints, xx: PdfPoints, yy: PdfPoints) -> (PdfPoints, PdfPoints) {
   let point = Point2::new(xx.value, yy.value);
@@ -40,182 +58,1451 @@ fn rot_to_norm(theta: f32, page_w: PdfPoints, x: PdfPoints, y: PdfPoints) -> (Pd
   (x, y)
 }
 
-fn mark_pdf(doc: &[u8], text: &str, font_size_pt: f32, theta_deg: f32) -> anyhow::Result<Vec<u8>> {
-  let doc = qpdf::QPdf::read_from_memory(doc)?;
-  let doc = doc.writer().preserve_encryption(false).write_to_memory()?;
+/// Size and contents of the watermark on a single page.
+#[derive(Clone)]
+struct MarkLayout {
+  /// Size of a single watermark.
+  content: (PdfPoints, PdfPoints),
+  /// Size of a single watermark including the gaps to its neighbours.
+  tile: (PdfPoints, PdfPoints),
+  /// Encoded text lines and their baselines relative to the watermark origin.
+  lines: Vec<(Object, PdfPoints, PdfPoints)>,
+}
+
+impl MarkLayout {
+  /// Number of tiles across and up a `page_w` x `page_h` page when tiled at `theta_deg`.
+  fn tile_grid(&self, theta_deg: f32, (page_w, page_h): (PdfPoints, PdfPoints)) -> (u32, u32) {
+    let (w, h) = self.tile;
+    let (w_max_r, _) = norm_to_rot(theta_deg, page_w, page_w, page_h);
+    let (_, h_max_r) = norm_to_rot(theta_deg, page_w, PdfPoints::zero(), page_h);
+    (
+      (w_max_r.value / w.value).ceil() as u32,
+      (h_max_r.value / h.value).ceil() as u32,
+    )
+  }
+}
+
+/// Origin of a single `text_w` x `text_h` stamp rotated by `theta_deg` around its centre, placed
+/// so that its bounding box sits at `anchor`, `offset` away from the anchored edges.
+fn placed_origin(
+  anchor: Anchor,
+  (offset_x, offset_y): (PdfPoints, PdfPoints),
+  theta_deg: f32,
+  (page_w, page_h): (PdfPoints, PdfPoints),
+  (text_w, text_h): (PdfPoints, PdfPoints),
+) -> (PdfPoints, PdfPoints) {
+  let (sin, cos) = theta_deg.to_radians().sin_cos();
+  let bbox_w = text_w * cos.abs() + text_h * sin.abs();
+  let bbox_h = text_w * sin.abs() + text_h * cos.abs();
+
+  let (align_x, align_y) = anchor.align();
+  let center_x = align_x.place(page_w, bbox_w, offset_x);
+  let center_y = align_y.place(page_h, bbox_h, offset_y);
+
+  // move the rotated text centre onto the bounding box centre
+  let (half_w, half_h) = (text_w / 2.0, text_h / 2.0);
+  (
+    center_x - (half_w * cos - half_h * sin),
+    center_y - (half_w * sin + half_h * cos),
+  )
+}
+
+/// Looks up an inheritable page attribute (ISO 32000-1 table 30) on the page or its ancestors in
+/// the page tree.
+pub(super) fn inherited_attribute(doc: &Document, page_id: ObjectId, key: &[u8]) -> lopdf::Result<Option<Object>> {
+  let mut node = doc.get_dictionary(page_id)?;
+  // the depth limit guards against cyclic page trees
+  for _ in 0..64 {
+    if let Ok(value) = node.get(key) {
+      return Ok(Some(doc.dereference(value)?.1.clone()));
+    }
+    match node.get(b"Parent") {
+      Ok(parent) => node = doc.dereference(parent)?.1.as_dict()?,
+      Err(_) => return Ok(None),
+    }
+  }
+  Err(lopdf::Error::ReferenceCycle)
+}
+
+/// Registers `id` as `name` in the `category` (e.g. `Font`) resources of a page. Resources the
+/// page inherits from the page tree are copied onto the page first, so that adding ours does not
+/// hide them.
+pub(super) fn add_page_resource(
+  doc: &mut Document,
+  page_id: ObjectId,
+  category: &str,
+  name: &str,
+  id: ObjectId,
+) -> lopdf::Result<()> {
+  if !doc.get_dictionary(page_id)?.has(b"Resources") {
+    if let Some(inherited) = inherited_attribute(doc, page_id, b"Resources")? {
+      doc.get_dictionary_mut(page_id)?.set("Resources", inherited);
+    }
+  }
+
+  let resources = doc.get_or_create_resources(page_id)?.as_dict_mut()?;
+  let category_id = match resources.get(category.as_bytes()) {
+    Ok(Object::Reference(category_id)) => Some(*category_id),
+    Ok(_) => None,
+    Err(_) => {
+      resources.set(category, Dictionary::new());
+      None
+    }
+  };
+  let category = match category_id {
+    Some(category_id) => doc.get_dictionary_mut(category_id)?,
+    None => doc
+      .get_or_create_resources(page_id)?
+      .as_dict_mut()?
+      .get_mut(category.as_bytes())?
+      .as_dict_mut()?,
+  };
+  category.set(name, id);
+  Ok(())
+}
+
+/// Surrounds the content streams of a page with `before` and `after` without decoding them. Empty
+/// streams are left out.
+pub(super) fn wrap_page_content(
+  doc: &mut Document,
+  page_id: ObjectId,
+  before: Vec<u8>,
+  after: Vec<u8>,
+) -> lopdf::Result<()> {
+  let mut contents = doc
+    .get_page_contents(page_id)
+    .into_iter()
+    .map(Object::Reference)
+    .collect::<Vec<_>>();
+  if !before.is_empty() {
+    contents.insert(0, doc.add_object(Stream::new(Dictionary::new(), before)).into());
+  }
+  if !after.is_empty() {
+    contents.push(doc.add_object(Stream::new(Dictionary::new(), after)).into());
+  }
+  doc.get_dictionary_mut(page_id)?.set("Contents", contents);
+  Ok(())
+}
+
+/// Prefix of the resource names of the forms drawing the tiles with `reuse_tiles`, followed by a
+/// number.
+pub(super) const FORM_PREFIX: &str = "FX_VATPRC";
+
+/// Name of the optional content group shown in the layers panel of viewers.
+pub(super) const OCG_NAME: &str = "VATPRC Watermark";
+
+/// Adds the optional content group of the watermark to `doc`, registering it in the document's
+/// `OCProperties` along with any groups the document already has.
+fn add_optional_content_group(doc: &mut Document, visibility: Visibility) -> lopdf::Result<ObjectId> {
+  let (view, print) = visibility.states();
+  let state = |on: bool| Object::Name(if on { "ON" } else { "OFF" }.into());
+  let ocg_id = doc.add_object(dictionary! {
+    "Type" => "OCG",
+    "Name" => Object::string_literal(OCG_NAME),
+    "Usage" => dictionary! {
+      "View" => dictionary! { "ViewState" => state(view) },
+      "Print" => dictionary! { "PrintState" => state(print) },
+    },
+  });
+
+  let dict = |dict: Option<&Object>| -> lopdf::Result<Dictionary> {
+    match dict {
+      Some(dict) => Ok(doc.dereference(dict)?.1.as_dict()?.clone()),
+      None => Ok(Dictionary::new()),
+    }
+  };
+  let mut properties = dict(doc.catalog()?.get(b"OCProperties").ok())?;
+  let mut config = dict(properties.get(b"D").ok())?;
+
+  let push = |dict: &mut Dictionary, key: &str, value: Object| -> lopdf::Result<()> {
+    let mut array = match dict.get(key.as_bytes()) {
+      Ok(array) => doc.dereference(array)?.1.as_array()?.clone(),
+      Err(_) => Vec::new(),
+    };
+    array.push(value);
+    dict.set(key, array);
+    Ok(())
+  };
+  push(&mut properties, "OCGs", ocg_id.into())?;
+  push(&mut config, "Order", ocg_id.into())?;
+  push(&mut config, if view { "ON" } else { "OFF" }, ocg_id.into())?;
+  // make viewers apply the usage states when viewing and printing
+  for event in ["View", "Print"] {
+    let usage = dictionary! {
+      "Event" => event,
+      "OCGs" => vec![ocg_id.into()],
+      "Category" => vec![event.into()],
+    };
+    push(&mut config, "AS", usage.into())?;
+  }
+  properties.set("D", config);
+
+  doc.catalog_mut()?.set("OCProperties", properties);
+  // optional content was introduced with PDF 1.5
+  if doc.version.as_str() < "1.5" {
+    doc.version = "1.5".to_owned();
+  }
+  Ok(ocg_id)
+}
+
+/// The part of a page a reader displays: the crop box (defaulting to the media box) in default
+/// user space, turned clockwise by `rotate` degrees.
+struct PageBox {
+  lower_left: (f32, f32),
+  upper_right: (f32, f32),
+  rotate: i64,
+}
+
+impl PageBox {
+  fn of(doc: &Document, page_id: ObjectId) -> lopdf::Result<Self> {
+    let rect = |key: &[u8]| -> lopdf::Result<Option<[f32; 4]>> {
+      let Some(rect) = inherited_attribute(doc, page_id, key)? else {
+        return Ok(None);
+      };
+      let values = rect
+        .as_array()?
+        .iter()
+        .map(|value| doc.dereference(value).and_then(|(_, value)| value.as_float()))
+        .collect::<lopdf::Result<Vec<_>>>()?;
+      let &[x0, y0, x1, y1] = values.as_slice() else {
+        return Err(lopdf::Error::Type);
+      };
+      // corners may be given in any order
+      Ok(Some([x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)]))
+    };
+
+    let media_box = rect(b"MediaBox")?.ok_or(lopdf::Error::DictKey)?;
+    let visible = match rect(b"CropBox")? {
+      Some(crop_box) => {
+        let clipped = [
+          crop_box[0].max(media_box[0]),
+          crop_box[1].max(media_box[1]),
+          crop_box[2].min(media_box[2]),
+          crop_box[3].min(media_box[3]),
+        ];
+        // an out of range crop box is ignored by readers
+        if clipped[0] < clipped[2] && clipped[1] < clipped[3] {
+          clipped
+        } else {
+          media_box
+        }
+      }
+      None => media_box,
+    };
+    let rotate = match inherited_attribute(doc, page_id, b"Rotate")? {
+      Some(rotate) => rotate.as_i64()?.rem_euclid(360) / 90 * 90,
+      None => 0,
+    };
+
+    Ok(Self {
+      lower_left: (visible[0], visible[1]),
+      upper_right: (visible[2], visible[3]),
+      rotate,
+    })
+  }
+
+  /// Width and height as displayed.
+  fn size(&self) -> (PdfPoints, PdfPoints) {
+    let w = PdfPoints::new(self.upper_right.0 - self.lower_left.0);
+    let h = PdfPoints::new(self.upper_right.1 - self.lower_left.1);
+    match self.rotate {
+      90 | 270 => (h, w),
+      _ => (w, h),
+    }
+  }
+
+  /// Operands of a `cm` mapping coordinates relative to the displayed lower left corner, x to
+  /// the right and y upwards as seen by the reader, onto default user space.
+  fn view_matrix(&self) -> Vec<Object> {
+    let ((llx, lly), (urx, ury)) = (self.lower_left, self.upper_right);
+    let matrix = match self.rotate {
+      90 => [0.0, 1.0, -1.0, 0.0, urx, lly],
+      180 => [-1.0, 0.0, 0.0, -1.0, urx, ury],
+      270 => [0.0, -1.0, 1.0, 0.0, llx, ury],
+      _ => [1.0, 0.0, 0.0, 1.0, llx, lly],
+    };
+    matrix.into_iter().map(Object::from).collect()
+  }
+}
+
+/// Classifies a qpdf failure on the uploaded document: anything qpdf blames on the file
+/// itself is the client's fault, the rest is ours.
+fn qpdf_input_error(err: qpdf::QPdfError) -> AppError {
+  use qpdf::QPdfErrorCode;
+  // spelled out, a misspelt variant would otherwise be a catch-all binding
+  match err.error_code() {
+    QPdfErrorCode::InvalidPassword => {
+      info!("rejecting document: {}", err);
+      DomainError::PdfPasswordError.into()
+    }
+    QPdfErrorCode::DamagedPdf | QPdfErrorCode::Unsupported | QPdfErrorCode::PagesError | QPdfErrorCode::ObjectError => {
+      info!("rejecting document: {}", err);
+      DomainError::PdfFormatError.into()
+    }
+    QPdfErrorCode::Unknown
+    | QPdfErrorCode::InvalidParameter
+    | QPdfErrorCode::InternalError
+    | QPdfErrorCode::SystemError => DomainError::PdfInternalError { cause: err.to_string() }.into(),
+  }
+}
+
+/// Classifies a lopdf failure while loading or walking the uploaded document. Missing keys and
+/// unexpected object types mean the document is malformed or uses structures we do not support.
+fn lopdf_input_error(err: lopdf::Error) -> AppError {
+  match err {
+    lopdf::Error::IO(_) => DomainError::PdfInternalError { cause: err.to_string() }.into(),
+    _ => {
+      info!("rejecting document: {}", err);
+      DomainError::PdfFormatError.into()
+    }
+  }
+}
+
+/// Anything failing on a document we have written ourselves is a bug.
+fn output_error(err: impl ToString) -> AppError {
+  DomainError::PdfInternalError { cause: err.to_string() }.into()
+}
+
+/// Bails out of `mark_pdf` once the request handler has given up waiting.
+fn check_cancelled(cancelled: &AtomicBool) -> anyhow::Result<()> {
+  if cancelled.load(Ordering::Relaxed) {
+    anyhow::bail!("watermarking cancelled after timeout");
+  }
+  Ok(())
+}
+
+/// Decrypts the uploaded document and loads it for editing.
+pub(super) fn load_document(upload: &MarkUpload) -> AppResult<Document> {
+  decrypt_document(upload, &open_document(upload)?)
+}
+
+/// Opens the upload with qpdf, using its password if it is encrypted.
+pub(super) fn open_document(upload: &MarkUpload) -> AppResult<qpdf::QPdf> {
+  match &upload.password {
+    Some(password) => qpdf::QPdf::read_encrypted(upload.pdf.path(), password),
+    None => qpdf::QPdf::read(upload.pdf.path()),
+  }
+  .map_err(qpdf_input_error)
+}
+
+/// Loads a document opened by `open_document` into lopdf, without its encryption.
+pub(super) fn decrypt_document(upload: &MarkUpload, doc: &qpdf::QPdf) -> AppResult<Document> {
+  let decrypted = upload.pdf.sibling();
+  doc
+    .writer()
+    .preserve_encryption(false)
+    .write(decrypted.path())
+    .map_err(qpdf_input_error)?;
+  Document::load(decrypted.path()).map_err(lopdf_input_error)
+}
+
+/// Watermarks the uploaded document into a new file next to it.
+pub(super) fn mark_pdf(upload: &MarkUpload, query: &MarkQuery, context: &MarkContext) -> AppResult<TempFile> {
+  let font = context.font.as_deref();
+  let (font_size_pt, theta_deg) = (query.font_size, query.rot_deg);
+
+  let mut doc = load_document(upload)?;
+
+  let image = upload
+    .image
+    .as_deref()
+    .map(WatermarkImage::decode)
+    .transpose()
+    .map_err(|err| {
+      info!("rejecting watermark image: {}", err);
+      DomainError::PdfImageFormatError
+    })?;
+  let template = TextTemplate::parse(&query.text).map_err(|_| DomainError::PdfInvalidParameter { name: "text" })?;
 
-  let font_size = PdfPoints::new(font_size_pt);
-  let w = PdfPoints::new(helvetica_width(text, font_size_pt));
-  let h = font_size;
-  let padding_w = PdfPoints::new(helvetica_width("xxxxxx", font_size_pt));
-  let padding_h = font_size / 2.0;
-  let (w, h) = (w + padding_w, h + padding_h);
   let theta_rad = theta_deg.to_radians();
 
-  let mut doc = Document::load_mem(&doc)?;
+  let page_ids = doc.page_iter().collect::<Vec<_>>();
+  if query.replace {
+    strip_watermark(&mut doc, &page_ids).map_err(lopdf_input_error)?;
+  }
+  if let Some(trace_id) = &query.trace_id {
+    embed_trace(&mut doc, &page_ids, trace_id).map_err(lopdf_input_error)?;
+  }
+  let page_count = page_ids.len();
+  // 1-based numbers and ids of the pages to watermark
+  let pages = page_ids
+    .iter()
+    .copied()
+    .enumerate()
+    .map(|(i, page_id)| (i + 1, page_id))
+    .filter(|&(page, _)| !(query.skip_first && page == 1))
+    .filter(|&(page, _)| {
+      query
+        .pages
+        .as_ref()
+        .is_none_or(|pages| pages.contains(page, page_count))
+    })
+    .collect::<Vec<_>>();
+  if pages.is_empty() {
+    // an unmarked copy would pass for a marked one
+    let name = if query.pages.is_some() { "pages" } else { "skip_first" };
+    return Err(DomainError::PdfInvalidParameter { name }.into());
+  }
 
-  // font
-  let font_id = doc.add_object(dictionary! {
-      "Type" => "Font",
-      "Subtype" => "Type1",
-      "BaseFont" => "Helvetica",
+  // watermark text of every page
+  let texts = match &image {
+    Some(_) => Vec::new(),
+    None => pages
+      .iter()
+      .map(|&(page, _)| template.render(&context.vars, page, page_count))
+      .collect::<Vec<_>>(),
+  };
+
+  // font or image
+  let (font_id, image_id) = match &image {
+    Some(image) => (None, Some(image.embed(&mut doc).map_err(output_error)?)),
+    None => {
+      let font_id = match font {
+        Some(font) => font
+          .embed(&mut doc, texts.iter().flat_map(|text| text.lines()))
+          .map_err(output_error)?,
+        None => doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        }),
+      };
+      (Some(font_id), None)
+    }
+  };
+
+  let font_size = PdfPoints::new(font_size_pt);
+  let leading = font_size * query.line_spacing.unwrap_or(DEFAULT_LINE_SPACING);
+  let text_width = |text: &str| match font {
+    Some(font) => font.width(text, font_size_pt),
+    None => helvetica_width(text, font_size_pt),
+  };
+  let layout = |text: Option<&str>| -> AppResult<MarkLayout> {
+    // size of a single watermark and default gaps between tiles
+    let (content, default_padding, lines) = match (&image, text) {
+      (Some(image), _) => {
+        let image_w = query
+          .image_width
+          .map(PdfPoints::new)
+          .unwrap_or(PdfPoints::new(image.width() as f32));
+        let image_h = image_w * (image.height() as f32 / image.width() as f32);
+        ((image_w, image_h), (image_w / 2.0, image_h / 2.0), Vec::new())
+      }
+      (None, text) => {
+        let lines = text.unwrap_or_default().lines().collect::<Vec<_>>();
+        let text_w = PdfPoints::new(lines.iter().map(|line| text_width(line)).fold(0.0, f32::max));
+        let text_h = font_size + leading * (lines.len().max(1) - 1) as f32;
+        // encoded lines and their baselines relative to the watermark origin, first line on top
+        let encoded_lines = lines
+          .iter()
+          .enumerate()
+          .map(|(i, line)| {
+            let encoded = match font {
+              Some(font) => font.encode(line),
+              None => Object::string_literal(*line),
+            };
+            let dx = query
+              .align
+              .unwrap_or_default()
+              .offset(text_w, PdfPoints::new(text_width(line)));
+            let dy = leading * (lines.len() - 1 - i) as f32;
+            (encoded, dx, dy)
+          })
+          .collect();
+        (
+          (text_w, text_h),
+          (PdfPoints::new(text_width("xxxxxx")), font_size / 2.0),
+          encoded_lines,
+        )
+      }
+    };
+    let padding_w = query.padding_w.map(PdfPoints::new).unwrap_or(default_padding.0);
+    let padding_h = query.padding_h.map(PdfPoints::new).unwrap_or(default_padding.1);
+    let tile = (content.0 + padding_w, content.1 + padding_h);
+    if tile.0.value <= 0.0 {
+      // blank text without padding would need infinitely many tiles
+      return Err(DomainError::PdfInvalidParameter { name: "padding_w" }.into());
+    }
+    Ok(MarkLayout { content, tile, lines })
+  };
+  let layouts = match &image {
+    Some(_) => vec![layout(None)?; pages.len()],
+    None => texts
+      .iter()
+      .map(|text| layout(Some(text)))
+      .collect::<AppResult<Vec<_>>>()?,
+  };
+
+  // graphics state (for opacity and blending)
+  let opacity = query.opacity.unwrap_or(DEFAULT_OPACITY);
+  let gs_id = doc.add_object(dictionary! {
+      "ca" => opacity,
+      "CA" => opacity,
+      "BM" => Object::Name(query.blend_mode.unwrap_or_default().pdf_name().into()),
   });
-  // graphics state (for opacity)
-  let gs_id = doc.add_object(dictionary! { "ca" => 0.05 });
+  // optional content group (for toggling and screen or print only watermarks)
+  let ocg_id = add_optional_content_group(&mut doc, query.visibility.unwrap_or_default()).map_err(lopdf_input_error)?;
 
-  for page_id in doc.page_iter().collect::<Vec<_>>().into_iter() {
-    // add font to page
-    let resources_dict = doc.get_or_create_resources(page_id)?.as_dict_mut()?;
-    if !resources_dict.has(b"Font") {
-      resources_dict.set(b"Font", Dictionary::new());
+  // Form XObjects of the tile grids drawn so far and their resource names, by text and page size
+  let mut forms = HashMap::<(String, u32, u32), (ObjectId, String)>::new();
+
+  let mut mark_page = |doc: &mut Document, page_id: ObjectId, text: &str, layout: &MarkLayout| -> lopdf::Result<()> {
+    let (content_w, content_h) = layout.content;
+    let (w, h) = layout.tile;
+
+    // add font or image to page, unless drawn by a form which has resources of its own
+    if !query.reuse_tiles {
+      if let Some(font_id) = font_id {
+        add_page_resource(doc, page_id, "Font", "F_VATPRC", font_id)?;
+      }
+      if let Some(image_id) = image_id {
+        add_page_resource(doc, page_id, "XObject", "IM_VATPRC", image_id)?;
+      }
     }
-    resources_dict
-      .get_mut("Font".as_bytes())?
-      .as_dict_mut()?
-      .set("F_VATPRC", font_id);
     // add graphics state to page
-    doc.add_graphics_state(page_id, "GS_VATPRC", gs_id)?;
+    add_page_resource(doc, page_id, "ExtGState", "GS_VATPRC", gs_id)?;
+    // add optional content group to page
+    add_page_resource(doc, page_id, "Properties", "OC_VATPRC", ocg_id)?;
 
-    // calculate page size
-    let page_media_box = doc.get_object(page_id)?.as_dict()?.get(b"MediaBox")?.as_array()?;
-    let (page_w, page_h) = (
-      PdfPoints::new(page_media_box[2].as_float()?),
-      PdfPoints::new(page_media_box[3].as_float()?),
-    );
+    // calculate page size as displayed
+    let page_box = PageBox::of(doc, page_id)?;
+    let (page_w, page_h) = page_box.size();
 
-    // calculate watermark count
-    let (w_max_r, _) = norm_to_rot(theta_deg, page_w, page_w, page_h);
-    let (_, h_max_r) = norm_to_rot(theta_deg, page_w, PdfPoints::zero(), page_h);
+    // calculate watermark origins
+    let origins = match query.mode.unwrap_or_default() {
+      MarkMode::Tiled => {
+        // calculate watermark count
+        let (total_i, total_j) = layout.tile_grid(theta_deg, (page_w, page_h));
 
-    let total_i = (w_max_r.value / w.value).ceil() as u32;
-    let total_j = (h_max_r.value / h.value).ceil() as u32;
-
-    // compute previous translation
-    let mut matrix = Matrix3::<f32>::identity();
-    let mut groups = Vec::new();
-    for operation in Content::decode(&doc.get_page_content(page_id)?)?.operations {
-      match operation.operator.as_ref() {
-        "cm" => {
-          if groups.is_empty() {
-            matrix = Matrix3::<f32>::new(
-              operation.operands[0].as_float()?,
-              operation.operands[1].as_float()?,
-              operation.operands[4].as_float()?,
-              operation.operands[2].as_float()?,
-              operation.operands[3].as_float()?,
-              operation.operands[5].as_float()?,
-              0.0,
-              0.0,
-              1.0,
-            ) * matrix
+        let mut origins = Vec::new();
+        for i in 0..total_i {
+          for j in 0..total_j {
+            let (x, y) = (w * i as f32, h * j as f32);
+            let (xx, yy) = rot_to_norm(theta_deg, page_w, x, y);
+            origins.push((xx - h * theta_rad.sin(), yy));
           }
         }
-        "q" => groups.push(()),
-        "Q" => {
-          groups.pop();
-        }
-        _ => {}
+        origins
       }
-    }
-    let inv = matrix.try_inverse().unwrap_or_else(Matrix3::identity);
+      MarkMode::Placed => {
+        let unit = query.offset_unit.unwrap_or_default();
+        let offset = (
+          unit.to_points(query.offset_x.unwrap_or(0.0)),
+          unit.to_points(query.offset_y.unwrap_or(0.0)),
+        );
+        let anchor = query.anchor.unwrap_or_default();
+        vec![placed_origin(
+          anchor,
+          offset,
+          theta_deg,
+          (page_w, page_h),
+          (content_w, content_h),
+        )]
+      }
+    };
 
     // generate watermarks
     // see https://github.com/Hopding/pdf-lib/blob/master/src/api/operations.ts#L52
     let mut operations = vec![
+      // enter optional content
+      Operation::new("BDC", vec!["OC".into(), "OC_VATPRC".into()]),
       // enter graphics group
       Operation::new("q", vec![]),
-      // cancel any previous translation
-      Operation::new(
-        "cm",
-        vec![
-          inv.m11.into(),
-          inv.m12.into(),
-          inv.m21.into(),
-          inv.m22.into(),
-          inv.m13.into(),
-          inv.m23.into(),
-        ],
-      ),
+      // lay out relative to the displayed page
+      Operation::new("cm", page_box.view_matrix()),
       // set graphics state
       Operation::new("gs", vec!["GS_VATPRC".into()]),
-      // begin text region
-      Operation::new("BT", vec![]),
-      // set font
-      Operation::new("Tf", vec!["F_VATPRC".into(), font_size.value.into()]),
     ];
 
-    for i in 0..total_i {
-      for j in 0..total_j {
-        let (x, y) = (w * i as f32, h * j as f32);
-        let (xx, yy) = rot_to_norm(theta_deg, page_w, x, y);
-        let (xx, yy) = (xx - h * theta_rad.sin(), yy);
-        // set transform matrix
-        operations.push(Operation::new(
-          "Tm",
+    // set colours and text rendering mode
+    if let Some(color) = query.color {
+      operations.push(color.operation(false));
+    }
+    if let Some(stroke_color) = query.stroke_color {
+      let stroke_width = query.stroke_width.unwrap_or(DEFAULT_STROKE_WIDTH);
+      let render_mode = if query.outline_only { 1 } else { 2 };
+      operations.push(stroke_color.operation(true));
+      operations.push(Operation::new("w", vec![stroke_width.into()]));
+      operations.push(Operation::new("Tr", vec![render_mode.into()]));
+    }
+
+    let mut tiles = Vec::new();
+    if image_id.is_some() {
+      for (x, y) in origins {
+        // scale the unit square to the image size, then rotate and move it
+        tiles.push(Operation::new("q", vec![]));
+        tiles.push(Operation::new(
+          "cm",
           vec![
-            theta_rad.cos().into(),
-            theta_rad.sin().into(),
-            (-theta_rad.sin()).into(),
-            theta_rad.cos().into(),
-            xx.value.into(),
-            yy.value.into(),
+            (content_w.value * theta_rad.cos()).into(),
+            (content_w.value * theta_rad.sin()).into(),
+            (-content_h.value * theta_rad.sin()).into(),
+            (content_h.value * theta_rad.cos()).into(),
+            x.value.into(),
+            y.value.into(),
           ],
         ));
-        // draw text
-        operations.push(Operation::new("Tj", vec![Object::string_literal(text)]));
+        // draw image
+        tiles.push(Operation::new("Do", vec!["IM_VATPRC".into()]));
+        tiles.push(Operation::new("Q", vec![]));
       }
+    } else {
+      // begin text region
+      tiles.push(Operation::new("BT", vec![]));
+      // set font
+      tiles.push(Operation::new("Tf", vec!["F_VATPRC".into(), font_size.value.into()]));
+
+      for (x, y) in origins {
+        for (encoded, dx, dy) in &layout.lines {
+          // rotate the line offset along with the text
+          let x = x + *dx * theta_rad.cos() - *dy * theta_rad.sin();
+          let y = y + *dx * theta_rad.sin() + *dy * theta_rad.cos();
+          // set transform matrix
+          tiles.push(Operation::new(
+            "Tm",
+            vec![
+              theta_rad.cos().into(),
+              theta_rad.sin().into(),
+              (-theta_rad.sin()).into(),
+              theta_rad.cos().into(),
+              x.value.into(),
+              y.value.into(),
+            ],
+          ));
+          // draw text
+          tiles.push(Operation::new("Tj", vec![encoded.clone()]));
+        }
+      }
+
+      // end text region
+      tiles.push(Operation::new("ET", vec![]));
     }
 
-    // end text region
-    operations.push(Operation::new("ET", vec![]));
+    if query.reuse_tiles {
+      // pages of the same size with the same text share a single form drawing their tiles
+      let key = (text.to_owned(), page_w.value.to_bits(), page_h.value.to_bits());
+      let (form_id, name) = match forms.get(&key) {
+        Some(form) => form.clone(),
+        None => {
+          let resources = match (font_id, image_id) {
+            (Some(font_id), _) => dictionary! { "Font" => dictionary! { "F_VATPRC" => font_id } },
+            (_, Some(image_id)) => dictionary! { "XObject" => dictionary! { "IM_VATPRC" => image_id } },
+            _ => Dictionary::new(),
+          };
+          let form = Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Form",
+                // the displayed page, tiles beyond it are not visible anyway
+                "BBox" => vec![0.into(), 0.into(), page_w.value.into(), page_h.value.into()],
+                "Resources" => resources,
+            },
+            Content { operations: tiles }.encode()?,
+          );
+          let form = (doc.add_object(form), format!("{}{}", FORM_PREFIX, forms.len()));
+          forms.insert(key, form.clone());
+          form
+        }
+      };
+      // shared resource dictionaries may hold several forms, hence a name per form
+      add_page_resource(doc, page_id, "XObject", &name, form_id)?;
+      operations.push(Operation::new("Do", vec![Object::Name(name.into_bytes())]));
+    } else {
+      operations.extend(tiles);
+    }
     // end graphics group
     operations.push(Operation::new("Q", vec![]));
+    // end optional content
+    operations.push(Operation::new("EMC", vec![]));
 
-    let content = Content { operations };
-    doc.add_to_page_content(page_id, content)?;
+    let content = Content { operations }.encode()?;
+    match query.layer.unwrap_or_default() {
+      // restore the default graphics state left by the original content before drawing
+      Layer::Foreground => wrap_page_content(doc, page_id, b"q\n".to_vec(), [b"Q\n".as_slice(), &content].concat()),
+      // the watermark restores its own graphics state before the original content starts
+      Layer::Background => wrap_page_content(doc, page_id, content, Vec::new()),
+    }
+  };
+
+  context.progress.pages_total.store(pages.len(), Ordering::Relaxed);
+  for (i, ((_, page_id), layout)) in pages.into_iter().zip(&layouts).enumerate() {
+    check_cancelled(&context.cancelled)?;
+    // drawing a page cannot be cancelled, so refuse watermarks small enough to take ages
+    if matches!(query.mode.unwrap_or_default(), MarkMode::Tiled) {
+      let page_size = PageBox::of(&doc, page_id).map_err(lopdf_input_error)?.size();
+      let (total_i, total_j) = layout.tile_grid(theta_deg, page_size);
+      if u64::from(total_i) * u64::from(total_j) > MAX_TILES_PER_PAGE {
+        let name = if image.is_some() { "image_width" } else { "font_size" };
+        return Err(DomainError::PdfInvalidParameter { name }.into());
+      }
+    }
+    let text = texts.get(i).map_or("", String::as_str);
+    mark_page(&mut doc, page_id, text, layout).map_err(lopdf_input_error)?;
+    context.progress.pages_done.fetch_add(1, Ordering::Relaxed);
   }
 
-  let mut vec = Vec::new();
-  doc.save_to(&mut vec)?;
+  check_cancelled(&context.cancelled)?;
 
-  let doc = qpdf::QPdf::read_from_memory(vec)?;
-  let vec = doc
-    .writer()
-    .encryption_params(qpdf::EncryptionParams::R6(qpdf::EncryptionParamsR6 {
+  let marked = upload.pdf.sibling();
+  doc.save(marked.path()).map_err(output_error)?;
+  // release the lopdf document before qpdf loads its own copy
+  drop(doc);
+
+  let doc = qpdf::QPdf::read(marked.path()).map_err(output_error)?;
+  let mut writer = doc.writer();
+  match query.permissions.unwrap_or_default().allow_print() {
+    Some(allow_print) => writer.encryption_params(qpdf::EncryptionParams::R6(qpdf::EncryptionParamsR6 {
       user_password: "".to_owned(),
-      owner_password: "9bd33204-8c7c-4cc2-8c22-70e09062c102".to_owned(),
+      owner_password: context.owner_password.clone(),
       allow_accessibility: false,
       allow_extract: false,
       allow_assemble: false,
       allow_annotate_and_form: false,
       allow_form_filling: false,
       allow_modify_other: false,
-      allow_print: qpdf::PrintPermission::Low,
+      allow_print,
       encrypt_metadata: false,
-    }))
-    .write_to_memory()?;
-  Ok(vec)
+    })),
+    None => writer.preserve_encryption(false),
+  };
+  writer.linearize(query.linearize);
+  if query.object_streams {
+    writer.object_stream_mode(qpdf::ObjectStreamMode::Generate);
+  }
+  if query.recompress {
+    // decode and compress all generally encoded streams again, including the watermark which
+    // lopdf writes uncompressed
+    writer
+      .stream_decode_level(qpdf::StreamDecodeLevel::Generalized)
+      .compress_streams(true);
+  }
+  let output = upload.pdf.sibling();
+  writer.write(output.path()).map_err(output_error)?;
+  Ok(output)
+}
+
+/// Server side inputs of a single `mark_pdf` run.
+#[derive(Clone)]
+pub(super) struct MarkContext {
+  font: Option<Arc<EmbeddedFont>>,
+  pub(super) vars: TemplateVars,
+  /// Owner password of the encrypted output.
+  owner_password: String,
+  /// Set once the request has given up waiting for the result.
+  pub(super) cancelled: Arc<AtomicBool>,
+  pub(super) progress: Arc<MarkProgress>,
+}
+
+#[cfg(test)]
+impl MarkContext {
+  /// A context drawing with the standard font, as without a configured font file.
+  pub(super) fn for_tests() -> Self {
+    Self {
+      font: None,
+      vars: TemplateVars::default(),
+      owner_password: String::new(),
+      cancelled: Arc::default(),
+      progress: Arc::default(),
+    }
+  }
+}
+
+/// Pages of a `mark_pdf` run, for reporting the progress of jobs.
+#[derive(Debug, Default)]
+pub(super) struct MarkProgress {
+  /// Number of pages to mark, 0 until the document has been loaded.
+  pub(super) pages_total: AtomicUsize,
+  pub(super) pages_done: AtomicUsize,
+}
+
+/// Whether `format` is a strftime format chrono can format dates with, it panics otherwise.
+fn is_valid_format(format: &str) -> bool {
+  StrftimeItems::new(format).all(|item| item != Item::Error)
+}
+
+/// Checks the watermark settings used by every request, so that they fail at startup instead.
+pub fn validate_settings(settings: &UtilsSettings) -> anyhow::Result<()> {
+  settings
+    .mark_pdf_timezone
+    .parse::<Tz>()
+    .map_err(|e| anyhow::anyhow!("invalid mark_pdf_timezone setting: {}", e))?;
+  if !is_valid_format(&settings.mark_pdf_date_format) {
+    anyhow::bail!("invalid mark_pdf_date_format setting");
+  }
+  if !is_valid_format(&settings.mark_pdf_datetime_format) {
+    anyhow::bail!("invalid mark_pdf_datetime_format setting");
+  }
+  Ok(())
+}
+
+impl MarkContext {
+  /// Resolves the template variables and output password of a request.
+  pub(super) fn new(state: &AppState, query: &MarkQuery, request_id: Ulid) -> AppResult<Self> {
+    let settings = &state.settings.utils;
+    let timezone = match &query.timezone {
+      Some(timezone) => timezone
+        .parse::<Tz>()
+        .map_err(|_| DomainError::PdfInvalidParameter { name: "timezone" })?,
+      None => settings
+        .mark_pdf_timezone
+        .parse::<Tz>()
+        .map_err(|e| anyhow::anyhow!("invalid mark_pdf_timezone setting: {}", e))?,
+    };
+    let now = Utc::now().with_timezone(&timezone);
+    let date_format = query.date_format.as_deref().unwrap_or(&settings.mark_pdf_date_format);
+    let datetime_format = query
+      .datetime_format
+      .as_deref()
+      .unwrap_or(&settings.mark_pdf_datetime_format);
+    let vars = TemplateVars {
+      date: now.format(date_format).to_string(),
+      datetime: now.format(datetime_format).to_string(),
+      request_id: request_id.to_string(),
+      user: query.user.clone().unwrap_or_default(),
+    };
+
+    Ok(Self {
+      font: state.watermark_font.clone(),
+      vars,
+      // without a configured password nobody, including us, can lift the restrictions
+      owner_password: settings
+        .mark_pdf_owner_password
+        .clone()
+        .unwrap_or_else(|| Ulid::new().to_string()),
+      cancelled: Arc::new(AtomicBool::new(false)),
+      progress: Arc::default(),
+    })
+  }
+}
+
+const DEFAULT_OPACITY: f32 = 0.05;
+const DEFAULT_STROKE_WIDTH: f32 = 1.0;
+const DEFAULT_LINE_SPACING: f32 = 1.2;
+/// Upper bound of the tiles of a single page, far more than any legible watermark needs.
+const MAX_TILES_PER_PAGE: u64 = 10_000;
+
+/// A device colour, written as `#rrggbb` for RGB or `c,m,y,k` with components between 0 and 1
+/// for CMYK.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Color {
+  Rgb(f32, f32, f32),
+  Cmyk(f32, f32, f32, f32),
+}
+
+impl TryFrom<String> for Color {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix('#') {
+      let channel = |i: usize| {
+        hex
+          .get(i..i + 2)
+          // `from_str_radix` would accept a sign
+          .filter(|c| c.bytes().all(|b| b.is_ascii_hexdigit()))
+          .and_then(|c| u8::from_str_radix(c, 16).ok())
+          .map(|c| c as f32 / 255.0)
+      };
+      return match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok(Color::Rgb(r, g, b)),
+        _ => Err(format!("invalid RGB colour `{}`, expected `#rrggbb`", value)),
+      };
+    }
+
+    let components = value
+      .split(',')
+      .map(|c| c.trim().parse::<f32>().ok().filter(|c| (0.0..=1.0).contains(c)))
+      .collect::<Option<Vec<_>>>();
+    match components.as_deref() {
+      Some(&[c, m, y, k]) => Ok(Color::Cmyk(c, m, y, k)),
+      _ => Err(format!(
+        "invalid CMYK colour `{}`, expected `c,m,y,k` between 0 and 1",
+        value
+      )),
+    }
+  }
+}
+
+impl Color {
+  /// Sets this colour as the stroking or non-stroking (fill) colour.
+  fn operation(&self, stroking: bool) -> Operation {
+    match *self {
+      Color::Rgb(r, g, b) => Operation::new(if stroking { "RG" } else { "rg" }, vec![r.into(), g.into(), b.into()]),
+      Color::Cmyk(c, m, y, k) => Operation::new(
+        if stroking { "K" } else { "k" },
+        vec![c.into(), m.into(), y.into(), k.into()],
+      ),
+    }
+  }
+}
+
+/// PDF blend modes, see ISO 32000-1 section 11.3.5.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+  #[default]
+  Normal,
+  Multiply,
+  Screen,
+  Overlay,
+  Darken,
+  Lighten,
+  ColorDodge,
+  ColorBurn,
+  HardLight,
+  SoftLight,
+  Difference,
+  Exclusion,
+  Hue,
+  Saturation,
+  Color,
+  Luminosity,
+}
+
+impl BlendMode {
+  fn pdf_name(&self) -> &'static str {
+    match self {
+      BlendMode::Normal => "Normal",
+      BlendMode::Multiply => "Multiply",
+      BlendMode::Screen => "Screen",
+      BlendMode::Overlay => "Overlay",
+      BlendMode::Darken => "Darken",
+      BlendMode::Lighten => "Lighten",
+      BlendMode::ColorDodge => "ColorDodge",
+      BlendMode::ColorBurn => "ColorBurn",
+      BlendMode::HardLight => "HardLight",
+      BlendMode::SoftLight => "SoftLight",
+      BlendMode::Difference => "Difference",
+      BlendMode::Exclusion => "Exclusion",
+      BlendMode::Hue => "Hue",
+      BlendMode::Saturation => "Saturation",
+      BlendMode::Color => "Color",
+      BlendMode::Luminosity => "Luminosity",
+    }
+  }
+}
+
+/// Whether the watermark is drawn over or under the page content.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+  /// Draw over the page content.
+  #[default]
+  Foreground,
+  /// Draw under the page content, hidden wherever the page paints an opaque background.
+  Background,
+}
+
+/// Where the watermark is visible.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+  /// Visible on screen and in print.
+  #[default]
+  Always,
+  /// Visible on screen, hidden in print.
+  ScreenOnly,
+  /// Hidden on screen, visible in print.
+  PrintOnly,
+}
+
+impl Visibility {
+  /// Whether the watermark is visible on screen and in print.
+  fn states(&self) -> (bool, bool) {
+    match self {
+      Visibility::Always => (true, true),
+      Visibility::ScreenOnly => (true, false),
+      Visibility::PrintOnly => (false, true),
+    }
+  }
+}
+
+/// How the watermark is laid out on every page.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkMode {
+  /// Repeat the text in a rotated grid covering the whole page.
+  #[default]
+  Tiled,
+  /// Draw the text once at `anchor`.
+  Placed,
+}
+
+/// One of nine positions on the page a placed watermark is aligned to.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+  TopLeft,
+  Top,
+  TopRight,
+  Left,
+  #[default]
+  Center,
+  Right,
+  BottomLeft,
+  Bottom,
+  BottomRight,
 }
 
-#[derive(Default, Deserialize)]
+#[derive(Debug, Clone, Copy)]
+enum Align {
+  Start,
+  Center,
+  End,
+}
+
+impl Anchor {
+  /// Horizontal and vertical alignment, vertical `Start` being the bottom of the page.
+  fn align(&self) -> (Align, Align) {
+    match self {
+      Anchor::TopLeft => (Align::Start, Align::End),
+      Anchor::Top => (Align::Center, Align::End),
+      Anchor::TopRight => (Align::End, Align::End),
+      Anchor::Left => (Align::Start, Align::Center),
+      Anchor::Center => (Align::Center, Align::Center),
+      Anchor::Right => (Align::End, Align::Center),
+      Anchor::BottomLeft => (Align::Start, Align::Start),
+      Anchor::Bottom => (Align::Center, Align::Start),
+      Anchor::BottomRight => (Align::End, Align::Start),
+    }
+  }
+}
+
+impl Align {
+  /// Centre of a box of `extent` along a page of `page` length. `offset` moves the box inwards
+  /// from the edge it is aligned to, or towards the end of the page when centred.
+  fn place(&self, page: PdfPoints, extent: PdfPoints, offset: PdfPoints) -> PdfPoints {
+    match self {
+      Align::Start => offset + extent / 2.0,
+      Align::Center => page / 2.0 + offset,
+      Align::End => page - offset - extent / 2.0,
+    }
+  }
+}
+
+/// Encryption and permissions of the watermarked document.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Permissions {
+  /// Encrypted, only low resolution printing is allowed.
+  #[default]
+  Restricted,
+  /// Encrypted, high quality printing is allowed.
+  Print,
+  /// Not encrypted, everything is allowed.
+  None,
+}
+
+impl Permissions {
+  /// Print permission of the encrypted output, `None` if the output is not encrypted.
+  fn allow_print(&self) -> Option<qpdf::PrintPermission> {
+    match self {
+      Permissions::Restricted => Some(qpdf::PrintPermission::Low),
+      Permissions::Print => Some(qpdf::PrintPermission::Full),
+      Permissions::None => None,
+    }
+  }
+}
+
+/// Horizontal alignment of the lines of a multi-line watermark.
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TextAlign {
+  #[default]
+  Left,
+  Center,
+  Right,
+}
+
+impl TextAlign {
+  /// Offset of a line of `line_w` within a watermark of `content_w`.
+  fn offset(&self, content_w: PdfPoints, line_w: PdfPoints) -> PdfPoints {
+    match self {
+      TextAlign::Left => PdfPoints::ZERO,
+      TextAlign::Center => (content_w - line_w) / 2.0,
+      TextAlign::Right => content_w - line_w,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthUnit {
+  #[default]
+  Pt,
+  Mm,
+}
+
+impl LengthUnit {
+  fn to_points(self, value: f32) -> PdfPoints {
+    match self {
+      LengthUnit::Pt => PdfPoints::new(value),
+      LengthUnit::Mm => PdfPoints::from_mm(value),
+    }
+  }
+}
+
+#[derive(Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct MarkQuery {
-  text: String,
+  /// Name of a preset of `/utils/mark/presets` providing defaults for the other parameters.
+  pub(super) preset: Option<String>,
+  /// The watermark text, may span multiple lines and contain the variables `{page}`, `{pages}`,
+  /// `{date}`, `{datetime}`, `{request_id}` and `{user}`. Must be omitted when an image is uploaded.
+  #[serde(default)]
+  pub(super) text: String,
+  /// Font size in points.
+  #[serde(default)]
   font_size: f32,
-  #[allow(dead_code)]
-  padding_w: f32,
-  #[allow(dead_code)]
-  padding_h: f32,
+  /// Pages to watermark, e.g. `1-3,7,last`, `5-last` or `odd`. Defaults to all pages.
+  #[param(value_type = Option<String>)]
+  pages: Option<PageSelection>,
+  /// Leave the first page (e.g. a cover sheet) unmarked, also when selected by `pages`.
+  #[serde(default)]
+  skip_first: bool,
+  /// Remove a watermark and trace id previously added by this service before marking, e.g. to
+  /// re-issue an already marked copy to a different recipient.
+  #[serde(default)]
+  replace: bool,
+  /// Identifier of the recipient embedded invisibly into the metadata and pages, up to 64 letters,
+  /// digits and `-_.:@`. Found again by `/utils/mark/trace`.
+  trace_id: Option<String>,
+  /// Encryption and permissions of the output, defaults to `restricted`.
+  #[param(inline)]
+  permissions: Option<Permissions>,
+  /// Value of `{user}` in the text.
+  user: Option<String>,
+  /// IANA time zone of `{date}` and `{datetime}` in the text, defaults to the server setting.
+  timezone: Option<String>,
+  /// strftime format of `{date}`, defaults to the server setting.
+  date_format: Option<String>,
+  /// strftime format of `{datetime}`, defaults to the server setting.
+  datetime_format: Option<String>,
+  /// Distance between baselines of multi-line text as a multiple of the font size, defaults to 1.2.
+  line_spacing: Option<f32>,
+  /// Alignment of the lines of multi-line text, defaults to `left`.
+  #[param(inline)]
+  align: Option<TextAlign>,
+  /// Horizontal gap between tiles in points, defaults to the width of six `x`, or half the image
+  /// width.
+  padding_w: Option<f32>,
+  /// Vertical gap between tiles in points, defaults to half the font size, or half the image height.
+  padding_h: Option<f32>,
+  /// Rotation of the watermark in degrees, counter-clockwise, defaults to 0.
+  #[serde(default)]
   rot_deg: f32,
+  /// Opacity between 0 and 1, defaults to 0.05.
+  opacity: Option<f32>,
+  /// Fill colour, `#rrggbb` or `c,m,y,k`, defaults to black.
+  #[param(value_type = Option<String>)]
+  color: Option<Color>,
+  /// Outline colour, `#rrggbb` or `c,m,y,k`. Text is not outlined if omitted.
+  #[param(value_type = Option<String>)]
+  stroke_color: Option<Color>,
+  /// Outline width in points, defaults to 1.
+  stroke_width: Option<f32>,
+  /// Draw only the outline without filling the glyphs. Requires `stroke_color`.
+  #[serde(default)]
+  outline_only: bool,
+  /// Width of the watermark image in points, defaults to one point per pixel. The height follows
+  /// the aspect ratio.
+  image_width: Option<f32>,
+  /// Blend mode of the watermark, e.g. `multiply` or `color_dodge`, defaults to `normal`.
+  #[param(inline)]
+  blend_mode: Option<BlendMode>,
+  /// Layout of the watermark, defaults to `tiled`.
+  #[param(inline)]
+  mode: Option<MarkMode>,
+  /// Draw the watermark over or under the page content, defaults to `foreground`.
+  #[param(inline)]
+  layer: Option<Layer>,
+  /// Show the watermark on screen, in print or both, defaults to `always`. The watermark is an
+  /// optional content group named "VATPRC Watermark" which viewers may also toggle.
+  #[param(inline)]
+  visibility: Option<Visibility>,
+  /// Position of a `placed` watermark, defaults to `center`.
+  #[param(inline)]
+  anchor: Option<Anchor>,
+  /// Horizontal offset of a `placed` watermark from its anchor, inwards from the left or right edge,
+  /// rightwards when centred.
+  offset_x: Option<f32>,
+  /// Vertical offset of a `placed` watermark from its anchor, inwards from the top or bottom edge,
+  /// upwards when centred.
+  offset_y: Option<f32>,
+  /// Unit of `offset_x` and `offset_y`, defaults to `pt`.
+  #[param(inline)]
+  offset_unit: Option<LengthUnit>,
+  /// Linearize the output for fast web view, so that viewers can show the first page before the
+  /// whole document has been downloaded.
+  #[serde(default)]
+  linearize: bool,
+  /// Pack objects into compressed object streams. Requires PDF 1.5 viewers, as does the watermark.
+  #[serde(default)]
+  object_streams: bool,
+  /// Decode and compress all streams again, including ones stored uncompressed in the upload.
+  #[serde(default)]
+  recompress: bool,
+  /// Draw the tiles once as a form reused on every page of the same size and text, instead of
+  /// repeating them on every page. Mostly useful without `{page}` in the text.
+  #[serde(default)]
+  reuse_tiles: bool,
 }
 
-pub async fn mark(query: Query<MarkQuery>, pdf: Bytes) -> AppResult<impl IntoResponse> {
-  info!("request received");
+impl MarkQuery {
+  pub(super) fn validate(&self, has_image: bool) -> Result<(), DomainError> {
+    self.validate_values()?;
+    let invalid = |name| {
+      info!("invalid watermark parameter `{}`", name);
+      Err(DomainError::PdfInvalidParameter { name })
+    };
+    if has_image {
+      if !self.text.is_empty() {
+        return invalid("text");
+      }
+    } else {
+      if self.text.is_empty() {
+        return invalid("text");
+      }
+      // any other value has been checked with the others
+      if self.font_size == 0.0 {
+        return invalid("font_size");
+      }
+    }
+    if self.outline_only && self.stroke_color.is_none() {
+      return invalid("outline_only");
+    }
+    Ok(())
+  }
 
-  let result = mark_pdf(&pdf, &query.text, query.font_size, query.rot_deg)?;
+  /// Checks the values of the parameters given, without requiring any or checking how they fit
+  /// together, e.g. of a preset which may be completed by a text or an image later.
+  pub(super) fn validate_values(&self) -> Result<(), DomainError> {
+    let invalid = |name| {
+      info!("invalid watermark parameter `{}`", name);
+      Err(DomainError::PdfInvalidParameter { name })
+    };
+    if !self.text.is_empty() && TextTemplate::parse(&self.text).is_err() {
+      return invalid("text");
+    }
+    // 0 when omitted
+    if self.font_size != 0.0 && !(self.font_size.is_finite() && self.font_size > 0.0) {
+      return invalid("font_size");
+    }
+    if self.line_spacing.is_some_and(|l| !(l.is_finite() && l > 0.0)) {
+      return invalid("line_spacing");
+    }
+    if self.image_width.is_some_and(|w| !(w.is_finite() && w > 0.0)) {
+      return invalid("image_width");
+    }
+    if self.timezone.as_deref().is_some_and(|t| t.parse::<Tz>().is_err()) {
+      return invalid("timezone");
+    }
+    if self.padding_w.is_some_and(|p| !(p.is_finite() && p >= 0.0)) {
+      return invalid("padding_w");
+    }
+    if self.padding_h.is_some_and(|p| !(p.is_finite() && p >= 0.0)) {
+      return invalid("padding_h");
+    }
+    if self.opacity.is_some_and(|o| !(0.0..=1.0).contains(&o)) {
+      return invalid("opacity");
+    }
+    if self.stroke_width.is_some_and(|w| !(w.is_finite() && w > 0.0)) {
+      return invalid("stroke_width");
+    }
+    if self.offset_x.is_some_and(|o| !o.is_finite()) {
+      return invalid("offset_x");
+    }
+    if self.offset_y.is_some_and(|o| !o.is_finite()) {
+      return invalid("offset_y");
+    }
+    if self.date_format.as_deref().is_some_and(|f| !is_valid_format(f)) {
+      return invalid("date_format");
+    }
+    if self.datetime_format.as_deref().is_some_and(|f| !is_valid_format(f)) {
+      return invalid("datetime_format");
+    }
+    if self.trace_id.as_deref().is_some_and(|t| !is_valid_trace_id(t)) {
+      return invalid("trace_id");
+    }
+    Ok(())
+  }
+}
+
+/// The upload of `/utils/mark`, either a raw `application/pdf` body or `multipart/form-data`
+/// with a `pdf` part and optional `image` and `password` parts. The password of an encrypted
+/// document may also be sent in the `X-Pdf-Password` header.
+pub struct MarkUpload {
+  /// The document, spilled to disk.
+  pub(super) pdf: Arc<TempFile>,
+  pub(super) image: Option<Bytes>,
+  pub(super) password: Option<String>,
+}
 
+/// Multipart form accepted by `/utils/mark`, only used for the OpenAPI document.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub(super) struct MarkForm {
+  /// The document to watermark.
+  #[schema(value_type = String, format = Binary)]
+  pdf: Vec<u8>,
+  /// A PNG or JPEG image drawn instead of the text.
+  #[schema(value_type = Option<String>, format = Binary)]
+  image: Option<Vec<u8>>,
+  /// Password of an encrypted document.
+  password: Option<String>,
+}
+
+/// The parts of a multipart upload to the watermark endpoints.
+pub(super) struct MarkParts {
+  /// File names and contents of the `pdf` parts, in upload order.
+  pub(super) pdfs: Vec<(Option<String>, Arc<TempFile>)>,
+  pub(super) image: Option<Bytes>,
+  pub(super) password: Option<String>,
+  pub(super) recipients: Vec<String>,
+}
+
+impl MarkParts {
+  pub(super) async fn read(req: Request, state: &AppState) -> Result<Self, Response> {
+    let settings = &state.settings.utils;
+    let spill_dir = spill_dir(settings);
+    let mut multipart = Multipart::from_request(req, state)
+      .await
+      .map_err(IntoResponse::into_response)?;
+    let mut parts = Self {
+      pdfs: Vec::new(),
+      image: None,
+      password: None,
+      recipients: Vec::new(),
+    };
+    while let Some(field) = multipart.next_field().await.map_err(IntoResponse::into_response)? {
+      match field.name() {
+        Some("pdf") => {
+          let file_name = field.file_name().map(str::to_owned);
+          let pdf = TempFile::spill(&spill_dir, field, settings.mark_pdf_max_size_byte).await?;
+          parts.pdfs.push((file_name, Arc::new(pdf)));
+        }
+        Some("image") => parts.image = Some(field.bytes().await.map_err(IntoResponse::into_response)?),
+        Some("password") => parts.password = Some(field.text().await.map_err(IntoResponse::into_response)?),
+        Some("recipient") => parts
+          .recipients
+          .push(field.text().await.map_err(IntoResponse::into_response)?),
+        _ => {}
+      }
+    }
+    // browsers send an empty part for an unset file input
+    parts.image = parts.image.filter(|image| !image.is_empty());
+    parts.password = parts.password.filter(|password| !password.is_empty());
+    Ok(parts)
+  }
+}
+
+#[async_trait]
+impl FromRequest<AppState> for MarkUpload {
+  type Rejection = Response;
+
+  async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+    state
+      .mark_pdf_queue
+      .check_capacity()
+      .map_err(IntoResponse::into_response)?;
+    let settings = &state.settings.utils;
+    let spill_dir = spill_dir(settings);
+    let header_password = req
+      .headers()
+      .get(PDF_PASSWORD_HEADER)
+      .and_then(|v| v.to_str().ok())
+      .map(str::to_owned);
+    let is_multipart = req
+      .headers()
+      .get(CONTENT_TYPE)
+      .and_then(|v| v.to_str().ok())
+      .is_some_and(|v| v.starts_with("multipart/form-data"));
+    if !is_multipart {
+      let body = req
+        .into_body()
+        .into_data_stream()
+        .map(|chunk| chunk.map_err(AppError::from));
+      let pdf = TempFile::spill(&spill_dir, body, settings.mark_pdf_max_size_byte).await?;
+      return Ok(Self {
+        pdf: Arc::new(pdf),
+        image: None,
+        password: header_password,
+      });
+    }
+
+    let mut parts = MarkParts::read(req, state).await?;
+    let Some((_, pdf)) = parts.pdfs.pop() else {
+      return Err(AppError::from(DomainError::PdfInvalidParameter { name: "pdf" }).into_response());
+    };
+    let (image, password) = (parts.image, parts.password.or(header_password));
+    Ok(Self { pdf, image, password })
+  }
+}
+
+/// Runs the synchronous qpdf and lopdf work of a request on the blocking pool, keeping it off the
+/// async workers. `permit` is held until the work has actually finished, also after a timeout:
+/// the blocking task cannot be aborted, so `cancelled` asks it to stop at the next page instead.
+pub(super) async fn run_blocking<T: Send + 'static>(
+  permit: Arc<OwnedSemaphorePermit>,
+  timeout: Duration,
+  cancelled: Option<&AtomicBool>,
+  work: impl FnOnce() -> AppResult<T> + Send + 'static,
+) -> AppResult<T> {
+  let task = tokio::task::spawn_blocking(move || {
+    let _permit = permit;
+    work()
+  });
+  match tokio::time::timeout(timeout, task).await {
+    Ok(result) => result?,
+    Err(_) => {
+      if let Some(cancelled) = cancelled {
+        cancelled.store(true, Ordering::Relaxed);
+      }
+      warn!("processing exceeded {:?}, cancelling", timeout);
+      Err(DomainError::PdfTimeout.into())
+    }
+  }
+}
+
+const PDF_PASSWORD_HEADER: &str = "x-pdf-password";
+
+#[utoipa::path(
+  post, path = "/utils/mark",
+  params(
+    MarkQuery,
+    ("X-Pdf-Password" = Option<String>, Header, description = "Password of an encrypted document"),
+  ),
+  request_body(content(
+    (Vec<u8> = "application/pdf"),
+    (inline(MarkForm) = "multipart/form-data"),
+  )),
+  responses((status = 200, body = Vec<u8>, content_type = "application/pdf")),
+)]
+pub async fn mark(State(state): State<AppState>, query: MarkQuery, upload: MarkUpload) -> AppResult<impl IntoResponse> {
+  let request_id = Ulid::new();
+  info!("request {} received", request_id);
+  query.validate(upload.image.is_some())?;
+
+  let context = MarkContext::new(&state, &query, request_id)?;
+  let cancelled = context.cancelled.clone();
+
+  let permit = state.mark_pdf_queue.acquire().await?;
+  let timeout = Duration::from_secs(state.settings.utils.mark_pdf_timeout_secs);
+  let output = run_blocking(Arc::new(permit), timeout, Some(&cancelled), move || {
+    mark_pdf(&upload, &query, &context)
+  })
+  .await?;
+
+  let (length, body) = output.into_body().await?;
   Ok((
-    AppendHeaders([(CONTENT_TYPE, "application/pdf"), (CONTENT_DISPOSITION, "inline")]),
-    result,
+    AppendHeaders([
+      (CONTENT_TYPE, "application/pdf".to_owned()),
+      (CONTENT_LENGTH, length.to_string()),
+      (CONTENT_DISPOSITION, "inline".to_owned()),
+      (HeaderName::from_static("x-request-id"), request_id.to_string()),
+    ]),
+    body,
   ))
 }
 
@@ -509,3 +1796,86 @@ mod text_width {
       .sum()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Maps a point relative to the displayed lower left corner onto default user space.
+  fn view_point(page: &PageBox, (x, y): (f32, f32)) -> (f32, f32) {
+    let matrix = page
+      .view_matrix()
+      .iter()
+      .map(|value| value.as_float().unwrap())
+      .collect::<Vec<_>>();
+    let &[a, b, c, d, e, f] = matrix.as_slice() else {
+      panic!("expected six operands, got {:?}", matrix);
+    };
+    (a * x + c * y + e, b * x + d * y + f)
+  }
+
+  #[test]
+  fn rotated_page_boxes() {
+    // (rotation, displayed size, displayed lower left, lower right and upper right in user space)
+    let cases = [
+      (0, (100.0, 200.0), (10.0, 20.0), (110.0, 20.0), (110.0, 220.0)),
+      (90, (200.0, 100.0), (110.0, 20.0), (110.0, 220.0), (10.0, 220.0)),
+      (180, (100.0, 200.0), (110.0, 220.0), (10.0, 220.0), (10.0, 20.0)),
+      (270, (200.0, 100.0), (10.0, 220.0), (10.0, 20.0), (110.0, 20.0)),
+    ];
+    for (rotate, size, lower_left, lower_right, upper_right) in cases {
+      let page = PageBox {
+        lower_left: (10.0, 20.0),
+        upper_right: (110.0, 220.0),
+        rotate,
+      };
+      let (w, h) = page.size();
+      assert_eq!((w.value, h.value), size, "size at {}°", rotate);
+      assert_eq!(view_point(&page, (0.0, 0.0)), lower_left, "lower left at {}°", rotate);
+      assert_eq!(
+        view_point(&page, (w.value, 0.0)),
+        lower_right,
+        "lower right at {}°",
+        rotate
+      );
+      assert_eq!(
+        view_point(&page, (w.value, h.value)),
+        upper_right,
+        "upper right at {}°",
+        rotate
+      );
+    }
+  }
+
+  fn color(value: &str) -> Result<Color, String> {
+    Color::try_from(value.to_owned())
+  }
+
+  #[test]
+  fn rgb_colors() {
+    assert_eq!(color("#000000"), Ok(Color::Rgb(0.0, 0.0, 0.0)));
+    assert_eq!(color("#FF00ff"), Ok(Color::Rgb(1.0, 0.0, 1.0)));
+    assert_eq!(color(" #336699 "), Ok(Color::Rgb(0.2, 0.4, 0.6)));
+    for value in ["#", "#fff", "#12345", "#1234567", "#gg0000", "#+10000", "#ÿÿÿ"] {
+      assert!(color(value).is_err(), "{:?} should be rejected", value);
+    }
+  }
+
+  #[test]
+  fn cmyk_colors() {
+    assert_eq!(color("0,0,0,1"), Ok(Color::Cmyk(0.0, 0.0, 0.0, 1.0)));
+    assert_eq!(color("0.1, 0.2 ,0.3,0.4"), Ok(Color::Cmyk(0.1, 0.2, 0.3, 0.4)));
+    for value in [
+      "",
+      "0,0,0",
+      "0,0,0,0,0",
+      "0,0,0,1.01",
+      "-0.1,0,0,0",
+      "0,0,0,x",
+      "0,0,,0",
+      "NaN,0,0,0",
+    ] {
+      assert!(color(value).is_err(), "{:?} should be rejected", value);
+    }
+  }
+}
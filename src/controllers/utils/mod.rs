@@ -1,3 +1,28 @@
+mod batch;
+mod font;
+mod forensic;
+mod image;
+mod inspect;
+mod jobs;
 mod mark_pdf;
+mod pages;
+mod presets;
+mod queue;
+mod spill;
+mod strip;
+mod template;
+mod zip;
 
-pub use mark_pdf::mark;
+pub use batch::*;
+pub use font::EmbeddedFont;
+pub use forensic::*;
+pub use image::WatermarkImage;
+pub use inspect::*;
+pub use jobs::*;
+pub use mark_pdf::*;
+pub use pages::PageSelection;
+pub use presets::*;
+pub use queue::*;
+pub use spill::{spill_dir, TempFile};
+pub use strip::strip_watermark;
+pub use template::{TemplateVars, TextTemplate};
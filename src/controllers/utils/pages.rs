@@ -0,0 +1,136 @@
+use serde::Deserialize;
+
+/// Pages to watermark, written as a comma separated list of page numbers, ranges such as `1-3`
+/// or `5-last`, and the keywords `first`, `last`, `odd` and `even`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PageSelection {
+  items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Item {
+  Range(Page, Page),
+  Odd,
+  Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Page {
+  Number(usize),
+  Last,
+}
+
+impl Page {
+  fn parse(value: &str) -> Result<Self, String> {
+    match value.trim() {
+      "first" => Ok(Page::Number(1)),
+      "last" => Ok(Page::Last),
+      value => match value.parse::<usize>() {
+        Ok(page) if page > 0 => Ok(Page::Number(page)),
+        _ => Err(format!(
+          "invalid page `{}`, expected a number from 1, `first` or `last`",
+          value
+        )),
+      },
+    }
+  }
+
+  fn resolve(&self, pages: usize) -> usize {
+    match *self {
+      Page::Number(page) => page,
+      Page::Last => pages,
+    }
+  }
+}
+
+impl TryFrom<String> for PageSelection {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    let items = value
+      .split(',')
+      .map(|item| match item.trim() {
+        "odd" => Ok(Item::Odd),
+        "even" => Ok(Item::Even),
+        item => match item.split_once('-') {
+          Some((start, end)) => match (Page::parse(start)?, Page::parse(end)?) {
+            (Page::Number(start), Page::Number(end)) if start > end => {
+              Err(format!("invalid range `{}`, the first page is after the last", item))
+            }
+            (Page::Last, Page::Number(_)) => Err(format!("invalid range `{}`, `last` must end a range", item)),
+            (start, end) => Ok(Item::Range(start, end)),
+          },
+          None => Page::parse(item).map(|page| Item::Range(page, page)),
+        },
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(Self { items })
+  }
+}
+
+impl PageSelection {
+  /// Whether the 1-based `page` out of `pages` is selected.
+  pub fn contains(&self, page: usize, pages: usize) -> bool {
+    let odd = page % 2 == 1;
+    self.items.iter().any(|item| match item {
+      Item::Range(start, end) => (start.resolve(pages)..=end.resolve(pages)).contains(&page),
+      Item::Odd => odd,
+      Item::Even => !odd,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn selection(value: &str) -> Result<PageSelection, String> {
+    PageSelection::try_from(value.to_owned())
+  }
+
+  fn selected(value: &str, pages: usize) -> Vec<usize> {
+    let selection = selection(value).unwrap();
+    (1..=pages).filter(|&page| selection.contains(page, pages)).collect()
+  }
+
+  #[test]
+  fn numbers_and_ranges() {
+    assert_eq!(selected("1-3,7", 10), [1, 2, 3, 7]);
+    assert_eq!(selected(" 2 , 4 - 5 ", 10), [2, 4, 5]);
+    assert_eq!(selected("3-3", 5), [3]);
+  }
+
+  #[test]
+  fn keywords() {
+    assert_eq!(selected("first", 5), [1]);
+    assert_eq!(selected("last", 5), [5]);
+    assert_eq!(selected("5-last", 7), [5, 6, 7]);
+    assert_eq!(selected("first-2", 7), [1, 2]);
+    assert_eq!(selected("odd", 5), [1, 3, 5]);
+    assert_eq!(selected("even", 5), [2, 4]);
+    assert_eq!(selected("even,last", 5), [2, 4, 5]);
+  }
+
+  #[test]
+  fn last_follows_the_document() {
+    assert_eq!(selected("2-last", 3), [2, 3]);
+    assert_eq!(selected("2-last", 1), Vec::<usize>::new());
+    assert_eq!(selected("last", 1), [1]);
+  }
+
+  #[test]
+  fn pages_beyond_the_document() {
+    assert_eq!(selected("8-12", 10), [8, 9, 10]);
+    assert_eq!(selected("11", 10), Vec::<usize>::new());
+  }
+
+  #[test]
+  fn invalid() {
+    for value in [
+      "", "0", "-1", "1-", "a", "1-b", "1,,2", "1-2-3", "odd-3", "5-3", "last-2",
+    ] {
+      assert!(selection(value).is_err(), "{:?} should be rejected", value);
+    }
+  }
+}
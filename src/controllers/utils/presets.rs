@@ -0,0 +1,232 @@
+use super::mark_pdf::MarkQuery;
+use crate::database::AsMarkPresetAccessor;
+use crate::{AppError, AppResult, AppState, DomainError};
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::Uri;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::info;
+use utoipa::openapi::path::ParameterIn;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct MarkPreset {
+  pub name: String,
+  pub description: String,
+  /// Query parameters of `/utils/mark` and their values.
+  pub parameters: BTreeMap<String, String>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<crate::database::MarkPreset> for MarkPreset {
+  fn from(p: crate::database::MarkPreset) -> Self {
+    Self {
+      name: p.name,
+      description: p.description,
+      // written by `encode_parameters`
+      parameters: serde_urlencoded::from_str(&p.parameters).unwrap_or_default(),
+      created_at: p.created_at,
+      updated_at: p.updated_at,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateMarkPreset {
+  /// Up to 64 lowercase letters, digits, `-` and `_`.
+  pub name: String,
+  #[serde(default)]
+  pub description: String,
+  /// Query parameters of `/utils/mark` and their values, e.g. `{"font_size": "24", "rot_deg": "45"}`.
+  pub parameters: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateMarkPreset {
+  #[serde(default)]
+  pub description: String,
+  pub parameters: BTreeMap<String, String>,
+}
+
+fn is_valid_preset_name(name: &str) -> bool {
+  (1..=64).contains(&name.len())
+    && name
+      .bytes()
+      .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Checks that `parameters` are valid values of `MarkQuery` and encodes them as a query string.
+fn encode_parameters(parameters: &BTreeMap<String, String>) -> AppResult<String> {
+  let invalid = || DomainError::PdfInvalidParameter { name: "parameters" };
+  let fields = MarkQuery::into_params(|| Some(ParameterIn::Query));
+  // presets do not nest
+  if let Some(key) = parameters
+    .keys()
+    .find(|&key| key == "preset" || !fields.iter().any(|field| &field.name == key))
+  {
+    info!("unknown preset parameter `{}`", key);
+    return Err(invalid().into());
+  }
+  let encoded = serde_urlencoded::to_string(parameters)?;
+  let query = serde_urlencoded::from_str::<MarkQuery>(&encoded).map_err(|_| invalid())?;
+  query.validate_values()?;
+  Ok(encoded)
+}
+
+/// Reads the query of the watermark endpoints, filling in the parameters missing from the request
+/// from the `preset`.
+#[async_trait]
+impl FromRequestParts<AppState> for MarkQuery {
+  type Rejection = Response;
+
+  async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+    let Query(query) = Query::<MarkQuery>::from_request_parts(parts, state)
+      .await
+      .map_err(IntoResponse::into_response)?;
+    let Some(name) = query.preset else {
+      return Ok(query);
+    };
+    let Some(preset) = state
+      .mark_preset_accessor()
+      .get(&name)
+      .await
+      .map_err(|err| AppError::from(err).into_response())?
+    else {
+      return Err(AppError::from(DomainError::MarkPresetNotFound { name }).into_response());
+    };
+
+    let Query(request) = Query::<Vec<(String, String)>>::from_request_parts(parts, state)
+      .await
+      .map_err(IntoResponse::into_response)?;
+    let mut parameters = serde_urlencoded::from_str::<Vec<(String, String)>>(&preset.parameters)
+      .map_err(|err| AppError::from(err).into_response())?;
+    // parameters of the request take precedence over the preset
+    parameters.retain(|(key, _)| !request.iter().any(|(request_key, _)| request_key == key));
+    parameters.extend(request);
+
+    // parse the result like any other query, so that errors are reported the same way
+    let uri = serde_urlencoded::to_string(&parameters)
+      .map_err(AppError::from)
+      .and_then(|query| Ok(format!("/?{}", query).parse::<Uri>()?))
+      .map_err(IntoResponse::into_response)?;
+    let Query(query) = Query::<MarkQuery>::try_from_uri(&uri).map_err(IntoResponse::into_response)?;
+    Ok(query)
+  }
+}
+
+#[utoipa::path(
+  get, path = "/utils/mark/presets",
+  responses((status = 200, body = Vec<MarkPreset>)),
+)]
+pub async fn list_presets(State(state): State<AppState>) -> AppResult<impl IntoResponse> {
+  let presets = state
+    .mark_preset_accessor()
+    .list()
+    .await?
+    .into_iter()
+    .map(Into::into)
+    .collect::<Vec<MarkPreset>>();
+  Ok(Json(presets))
+}
+
+#[utoipa::path(
+  get, path = "/utils/mark/presets/{name}",
+  responses((status = 200, body = MarkPreset)),
+  params(("name" = String, Path, description = "The name of the preset")),
+)]
+pub async fn get_preset(State(state): State<AppState>, Path(name): Path<String>) -> AppResult<impl IntoResponse> {
+  let Some(preset) = state.mark_preset_accessor().get(&name).await?.map(MarkPreset::from) else {
+    return Err(DomainError::MarkPresetNotFound { name }.into());
+  };
+  Ok(Json(preset))
+}
+
+#[utoipa::path(
+  post, path = "/utils/mark/presets",
+  responses((status = 200, body = MarkPreset)),
+)]
+pub async fn create_preset(
+  State(state): State<AppState>,
+  Json(payload): Json<CreateMarkPreset>,
+) -> AppResult<impl IntoResponse> {
+  if !is_valid_preset_name(&payload.name) {
+    return Err(DomainError::PdfInvalidParameter { name: "name" }.into());
+  }
+  let parameters = encode_parameters(&payload.parameters)?;
+  let Some(preset) = state
+    .mark_preset_accessor()
+    .create(&payload.name, &payload.description, &parameters)
+    .await?
+  else {
+    return Err(DomainError::MarkPresetExists { name: payload.name }.into());
+  };
+  Ok(Json(MarkPreset::from(preset)))
+}
+
+#[utoipa::path(
+  put, path = "/utils/mark/presets/{name}",
+  responses((status = 200, body = MarkPreset)),
+  params(("name" = String, Path, description = "The name of the preset")),
+)]
+pub async fn update_preset(
+  State(state): State<AppState>,
+  Path(name): Path<String>,
+  Json(payload): Json<UpdateMarkPreset>,
+) -> AppResult<impl IntoResponse> {
+  let parameters = encode_parameters(&payload.parameters)?;
+  let Some(preset) = state
+    .mark_preset_accessor()
+    .update(&name, &payload.description, &parameters)
+    .await?
+  else {
+    return Err(DomainError::MarkPresetNotFound { name }.into());
+  };
+  Ok(Json(MarkPreset::from(preset)))
+}
+
+#[utoipa::path(
+  delete,
+  path = "/utils/mark/presets/{name}",
+  responses((status = 200, body = MarkPreset)),
+  params(("name" = String, Path, description = "The name of the preset")),
+)]
+pub async fn delete_preset(State(state): State<AppState>, Path(name): Path<String>) -> AppResult<impl IntoResponse> {
+  let Some(preset) = state.mark_preset_accessor().delete(&name).await? else {
+    return Err(DomainError::MarkPresetNotFound { name }.into());
+  };
+  Ok(Json(MarkPreset::from(preset)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(parameters: &[(&str, &str)]) -> AppResult<String> {
+    encode_parameters(&parameters.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+  }
+
+  #[test]
+  fn parameters() {
+    assert_eq!(
+      encode(&[("rot_deg", "45"), ("font_size", "24")]).unwrap(),
+      "font_size=24&rot_deg=45"
+    );
+    assert!(encode(&[("opacity", "0.2"), ("color", "#ff0000")]).is_ok());
+    assert!(encode(&[]).is_ok());
+    // the text or image is left to the request
+    assert!(encode(&[("outline_only", "true")]).is_ok());
+
+    assert!(encode(&[("fontsize", "24")]).is_err());
+    assert!(encode(&[("preset", "other")]).is_err());
+    assert!(encode(&[("opacity", "5")]).is_err());
+    assert!(encode(&[("font_size", "-1")]).is_err());
+    assert!(encode(&[("font_size", "large")]).is_err());
+    assert!(encode(&[("text", "{unknown}")]).is_err());
+    assert!(encode(&[("timezone", "Mars/Olympus")]).is_err());
+  }
+}
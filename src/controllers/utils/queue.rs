@@ -0,0 +1,123 @@
+use crate::settings::UtilsSettings;
+use crate::{AppResult, AppState, DomainError};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use utoipa::ToSchema;
+
+/// Limits how many `mark_pdf` jobs run at once, with a bounded number of requests waiting for a
+/// free slot. Requests beyond that are turned away instead of piling up.
+pub struct JobQueue {
+  permits: Arc<Semaphore>,
+  max_jobs: usize,
+  max_queue: usize,
+  retry_after_secs: u64,
+  queued: AtomicUsize,
+}
+
+/// Decrements the queue depth when a waiting request leaves the queue, also when it is dropped
+/// while waiting.
+pub struct Queued(Arc<JobQueue>);
+
+impl Drop for Queued {
+  fn drop(&mut self) {
+    self.0.queued.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// A request let into the queue, either with a slot already or with a place among the waiting.
+pub enum Admission {
+  Running(OwnedSemaphorePermit),
+  Queued(Queued),
+}
+
+impl Admission {
+  /// Waits for the slot, which is held until the permit is dropped.
+  pub async fn wait(self) -> AppResult<OwnedSemaphorePermit> {
+    match self {
+      Self::Running(permit) => Ok(permit),
+      Self::Queued(queued) => Ok(queued.0.permits.clone().acquire_owned().await?),
+    }
+  }
+}
+
+impl JobQueue {
+  pub fn new(settings: &UtilsSettings) -> Self {
+    Self {
+      permits: Arc::new(Semaphore::new(settings.mark_pdf_max_jobs)),
+      max_jobs: settings.mark_pdf_max_jobs,
+      max_queue: settings.mark_pdf_max_queue,
+      retry_after_secs: settings.mark_pdf_retry_after_secs,
+      queued: AtomicUsize::new(0),
+    }
+  }
+
+  fn busy(&self) -> DomainError {
+    DomainError::PdfBusy {
+      retry_after_secs: self.retry_after_secs,
+    }
+  }
+
+  /// Turns a request away early if `acquire` would, so that uploads are not written to disk only
+  /// to be rejected. `acquire` still decides, the queue may have filled up in the meantime.
+  pub fn check_capacity(&self) -> AppResult<()> {
+    if self.permits.available_permits() == 0 && self.queued.load(Ordering::Relaxed) >= self.max_queue {
+      return Err(self.busy().into());
+    }
+    Ok(())
+  }
+
+  /// Waits for a slot to run a job in, which is held until the permit is dropped.
+  pub async fn acquire(self: &Arc<Self>) -> AppResult<OwnedSemaphorePermit> {
+    self.admit()?.wait().await
+  }
+
+  /// A slot that is free right now, without waiting or taking a place in the queue.
+  pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+    self.permits.clone().try_acquire_owned().ok()
+  }
+
+  /// Takes a place in the queue without waiting for it to come up, or turns the request away if
+  /// the queue is full.
+  pub fn admit(self: &Arc<Self>) -> AppResult<Admission> {
+    if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+      return Ok(Admission::Running(permit));
+    }
+    let queued = Queued(self.clone());
+    if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queue {
+      return Err(self.busy().into());
+    }
+    Ok(Admission::Queued(queued))
+  }
+
+  pub fn status(&self) -> QueueStatus {
+    QueueStatus {
+      running: self.max_jobs - self.permits.available_permits(),
+      queued: self.queued.load(Ordering::Relaxed).min(self.max_queue),
+      max_jobs: self.max_jobs,
+      max_queue: self.max_queue,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QueueStatus {
+  /// Jobs running right now.
+  pub running: usize,
+  /// Requests and accepted jobs of `/utils/mark/jobs` waiting for a job to finish.
+  pub queued: usize,
+  pub max_jobs: usize,
+  pub max_queue: usize,
+}
+
+#[utoipa::path(
+  get, path = "/utils/mark/status",
+  responses((status = 200, body = QueueStatus)),
+)]
+pub async fn mark_status(State(state): State<AppState>) -> impl IntoResponse {
+  Json(state.mark_pdf_queue.status())
+}
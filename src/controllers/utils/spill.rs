@@ -0,0 +1,100 @@
+use crate::settings::UtilsSettings;
+use crate::{AppError, DomainError};
+use axum::body::{Body, Bytes};
+use axum::response::{IntoResponse, Response};
+use futures::{Stream, StreamExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use ulid::Ulid;
+
+/// Size of the chunks a spilled file is sent back in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Directory uploads are spilled to.
+pub fn spill_dir(settings: &UtilsSettings) -> PathBuf {
+  match &settings.mark_pdf_temp_dir {
+    Some(dir) => PathBuf::from(dir),
+    None => std::env::temp_dir(),
+  }
+}
+
+/// A file in the spill directory, removed when dropped.
+///
+/// Uploads and intermediate documents are kept on disk instead of in memory, so that the size of
+/// the documents we accept is not bounded by the memory of concurrent requests.
+#[derive(Debug)]
+pub struct TempFile {
+  path: PathBuf,
+}
+
+impl TempFile {
+  pub fn new(dir: &Path) -> Self {
+    Self {
+      path: dir.join(format!("vatprc-mark-{}.pdf", Ulid::new())),
+    }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// A new file in the same directory.
+  pub fn sibling(&self) -> Self {
+    Self::new(self.path.parent().unwrap_or(Path::new(".")))
+  }
+
+  /// Writes `chunks` to a new file in `dir`, rejecting uploads of more than `limit` bytes.
+  pub async fn spill<E: IntoResponse>(
+    dir: &Path,
+    chunks: impl Stream<Item = Result<Bytes, E>>,
+    limit: usize,
+  ) -> Result<Self, Response> {
+    let internal = |err: std::io::Error| AppError::from(err).into_response();
+    let temp = Self::new(dir);
+    let mut file = tokio::fs::File::create(&temp.path).await.map_err(internal)?;
+    let mut size = 0;
+    let mut chunks = std::pin::pin!(chunks);
+    while let Some(chunk) = chunks.next().await {
+      let chunk = chunk.map_err(IntoResponse::into_response)?;
+      size += chunk.len();
+      if size > limit {
+        return Err(AppError::from(DomainError::PdfTooLarge).into_response());
+      }
+      file.write_all(&chunk).await.map_err(internal)?;
+    }
+    file.flush().await.map_err(internal)?;
+    Ok(temp)
+  }
+
+  /// Streams the file as a response body along with its length. The file is removed right away,
+  /// its contents stay readable until the body has been sent.
+  pub async fn into_body(self) -> std::io::Result<(u64, Body)> {
+    let (length, chunks) = self.into_stream().await?;
+    Ok((length, Body::from_stream(chunks)))
+  }
+
+  /// Reads the file in chunks along with its length, removing it like `into_body`.
+  pub async fn into_stream(self) -> std::io::Result<(u64, impl Stream<Item = std::io::Result<Bytes>>)> {
+    let file = tokio::fs::File::open(&self.path).await?;
+    let length = file.metadata().await?.len();
+    drop(self);
+
+    let chunks = futures::stream::try_unfold(file, |mut file| async move {
+      let mut chunk = vec![0; CHUNK_SIZE];
+      let read = file.read(&mut chunk).await?;
+      if read == 0 {
+        return Ok(None);
+      }
+      chunk.truncate(read);
+      Ok(Some((Bytes::from(chunk), file)))
+    });
+    Ok((length, chunks))
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    // the file may never have been written
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
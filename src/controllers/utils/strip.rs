@@ -0,0 +1,343 @@
+use super::mark_pdf::{FORM_PREFIX, OCG_NAME};
+use super::strip_trace;
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document, Object, ObjectId};
+
+/// Resources added to pages by `mark_pdf`, by category.
+const RESOURCES: [(&[u8], &[u8]); 5] = [
+  (b"Font", b"F_VATPRC"),
+  (b"Font", b"FT_VATPRC"),
+  (b"ExtGState", b"GS_VATPRC"),
+  (b"XObject", b"IM_VATPRC"),
+  (b"Properties", b"OC_VATPRC"),
+];
+
+/// Removes a watermark previously added by this service from `doc`, so that it can be marked
+/// again for a different recipient.
+///
+/// Only content streams mentioning our resource names are decoded. The watermark is recognised by
+/// its marked-content section tagged with `OC_VATPRC`, the trace text by its `FT_VATPRC` font.
+/// Forms drawing the tiles are left unreferenced and dropped when the document is written.
+pub fn strip_watermark(doc: &mut Document, page_ids: &[ObjectId]) -> lopdf::Result<()> {
+  for &page_id in page_ids {
+    strip_page(doc, page_id)?;
+  }
+  strip_optional_content_groups(doc)?;
+  strip_trace(doc, page_ids)
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+  data.windows(needle.len()).any(|window| window == needle)
+}
+
+fn is_name(operand: Option<&Object>, name: &[u8]) -> bool {
+  operand.and_then(|o| o.as_name().ok()) == Some(name)
+}
+
+fn strip_page(doc: &mut Document, page_id: ObjectId) -> lopdf::Result<()> {
+  let mut contents = Vec::new();
+  for stream_id in doc.get_page_contents(page_id) {
+    let data = doc
+      .get_object(stream_id)
+      .and_then(Object::as_stream)
+      .and_then(|stream| stream.get_plain_content());
+    let data = match data {
+      Ok(data) if contains(&data, b"OC_VATPRC") || contains(&data, b"FT_VATPRC") => data,
+      _ => {
+        contents.push(Object::Reference(stream_id));
+        continue;
+      }
+    };
+
+    let operations = Content::decode(&data)?.operations;
+    // the trace text is drawn by a stream of its own
+    if operations
+      .iter()
+      .any(|operation| operation.operator == "Tf" && is_name(operation.operands.first(), b"FT_VATPRC"))
+    {
+      continue;
+    }
+
+    let mut kept = Vec::<Operation>::new();
+    // depth of marked-content sections within the watermark, 0 outside of it
+    let mut depth = 0;
+    for operation in operations {
+      match operation.operator.as_str() {
+        "BDC" if depth == 0 && is_name(operation.operands.get(1), b"OC_VATPRC") => depth = 1,
+        "BDC" | "BMC" if depth > 0 => depth += 1,
+        "EMC" if depth > 0 => depth -= 1,
+        _ if depth > 0 => {}
+        _ => kept.push(operation),
+      }
+    }
+    if kept.is_empty() {
+      continue;
+    }
+    let content = Content { operations: kept }.encode()?;
+    doc
+      .get_object_mut(stream_id)?
+      .as_stream_mut()?
+      .set_plain_content(content);
+    contents.push(Object::Reference(stream_id));
+  }
+  doc.get_dictionary_mut(page_id)?.set("Contents", contents);
+
+  // inherited resources are copied onto the page when marking, so only the page's own matter
+  if !doc.get_dictionary(page_id)?.has(b"Resources") {
+    return Ok(());
+  }
+  for (category, name) in RESOURCES {
+    remove_page_resources(doc, page_id, category, |key| key == name)?;
+  }
+  remove_page_resources(doc, page_id, b"XObject", |key| key.starts_with(FORM_PREFIX.as_bytes()))
+}
+
+/// Removes the resources of a category of the page matching `is_ours`.
+fn remove_page_resources(
+  doc: &mut Document,
+  page_id: ObjectId,
+  category: &[u8],
+  is_ours: impl Fn(&[u8]) -> bool,
+) -> lopdf::Result<()> {
+  // removes our resources of a category, telling whether there were any
+  let remove = |category: &mut Dictionary| {
+    let keys = category
+      .iter()
+      .map(|(key, _)| key.clone())
+      .filter(|key| is_ours(key))
+      .collect::<Vec<_>>();
+    for key in &keys {
+      category.remove(key);
+    }
+    !keys.is_empty()
+  };
+  let resources = doc.get_or_create_resources(page_id)?.as_dict_mut()?;
+  let category_id = match resources.get_mut(category) {
+    Ok(Object::Reference(category_id)) => *category_id,
+    Ok(Object::Dictionary(dict)) => {
+      // drop categories added by marking along with our resources
+      if remove(dict) && dict.is_empty() {
+        resources.remove(category);
+      }
+      return Ok(());
+    }
+    _ => return Ok(()),
+  };
+  if let Ok(category) = doc.get_dictionary_mut(category_id) {
+    remove(category);
+  }
+  Ok(())
+}
+
+/// Removes our optional content groups from the document's `OCProperties`.
+fn strip_optional_content_groups(doc: &mut Document) -> lopdf::Result<()> {
+  let Ok(properties) = doc.catalog()?.get(b"OCProperties") else {
+    return Ok(());
+  };
+  let mut properties = doc.dereference(properties)?.1.as_dict()?.clone();
+  let array = |object: Option<&Object>| -> lopdf::Result<Vec<Object>> {
+    match object {
+      Some(object) => Ok(doc.dereference(object)?.1.as_array()?.clone()),
+      None => Ok(Vec::new()),
+    }
+  };
+
+  let ocgs = array(properties.get(b"OCGs").ok())?;
+  let ours = ocgs
+    .iter()
+    .filter_map(|ocg| ocg.as_reference().ok())
+    .filter(|&id| {
+      let name = doc
+        .get_dictionary(id)
+        .and_then(|ocg| ocg.get(b"Name"))
+        .and_then(Object::as_str);
+      name.is_ok_and(|name| name == OCG_NAME.as_bytes())
+    })
+    .collect::<Vec<_>>();
+  if ours.is_empty() {
+    return Ok(());
+  }
+  let is_ours = |object: &Object| object.as_reference().is_ok_and(|id| ours.contains(&id));
+
+  let mut config = match properties.get(b"D") {
+    Ok(config) => doc.dereference(config)?.1.as_dict()?.clone(),
+    Err(_) => Dictionary::new(),
+  };
+  // arrays left empty were added by marking, or held nothing but ours
+  let set = |config: &mut Dictionary, key: &str, array: Vec<Object>| {
+    if array.is_empty() {
+      config.remove(key.as_bytes());
+    } else {
+      config.set(key, array);
+    }
+  };
+  for key in ["Order", "ON", "OFF", "Locked"] {
+    if config.has(key.as_bytes()) {
+      let filtered = array(config.get(key.as_bytes()).ok())?
+        .into_iter()
+        .filter(|ocg| !is_ours(ocg))
+        .collect::<Vec<_>>();
+      set(&mut config, key, filtered);
+    }
+  }
+  if config.has(b"AS") {
+    let mut usages = Vec::new();
+    for usage in array(config.get(b"AS").ok())? {
+      let usage_ocgs = doc
+        .dereference(&usage)?
+        .1
+        .as_dict()?
+        .get(b"OCGs")
+        .ok()
+        .map(|ocgs| array(Some(ocgs)))
+        .transpose()?
+        .unwrap_or_default();
+      if !usage_ocgs.iter().all(is_ours) {
+        usages.push(usage);
+      }
+    }
+    set(&mut config, "AS", usages);
+  }
+
+  let ocgs = ocgs.into_iter().filter(|ocg| !is_ours(ocg)).collect::<Vec<_>>();
+  if ocgs.is_empty() {
+    doc.catalog_mut()?.remove(b"OCProperties");
+    return Ok(());
+  }
+  properties.set("OCGs", ocgs);
+  properties.set("D", config);
+  doc.catalog_mut()?.set("OCProperties", properties);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::mark_pdf::{mark_pdf, MarkContext, MarkQuery, MarkUpload};
+  use super::super::spill::TempFile;
+  use super::*;
+  use lopdf::{dictionary, Stream};
+  use std::sync::Arc;
+
+  /// A page with a font and an optional content group of its own, which must survive stripping.
+  fn sample_document() -> Document {
+    let mut doc = Document::with_version("1.7");
+    let pages_id = doc.new_object_id();
+    let font_id = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Courier" });
+    let ocg_id = doc.add_object(dictionary! { "Type" => "OCG", "Name" => Object::string_literal("Notes") });
+    let content = b"BT /F1 12 Tf 10 10 Td (Hello) Tj ET /OC /MC0 BDC 0 0 m 100 100 l S EMC".to_vec();
+    let content_id = doc.add_object(Stream::new(dictionary! {}, content));
+    let page_id = doc.add_object(dictionary! {
+      "Type" => "Page",
+      "Parent" => pages_id,
+      "MediaBox" => vec![0.into(), 0.into(), 200.into(), 300.into()],
+      "Contents" => content_id,
+      "Resources" => dictionary! {
+        "Font" => dictionary! { "F1" => font_id },
+        "Properties" => dictionary! { "MC0" => ocg_id },
+      },
+    });
+    doc.objects.insert(
+      pages_id,
+      Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page_id.into()], "Count" => 1 }),
+    );
+    let catalog_id = doc.add_object(dictionary! {
+      "Type" => "Catalog",
+      "Pages" => pages_id,
+      "OCProperties" => dictionary! {
+        "OCGs" => vec![ocg_id.into()],
+        "D" => dictionary! { "Order" => vec![ocg_id.into()], "ON" => vec![ocg_id.into()] },
+      },
+    });
+    doc.trailer.set("Root", catalog_id);
+    doc
+  }
+
+  /// `object` with its references replaced by what they point to, so that documents numbering
+  /// their objects differently can be compared.
+  fn resolve(doc: &Document, object: &Object) -> Object {
+    match object {
+      Object::Reference(id) => resolve(doc, doc.get_object(*id).unwrap()),
+      Object::Array(array) => Object::Array(array.iter().map(|object| resolve(doc, object)).collect()),
+      Object::Dictionary(dict) => Object::Dictionary(
+        dict
+          .iter()
+          .map(|(key, object)| (key.clone(), resolve(doc, object)))
+          .collect::<Dictionary>(),
+      ),
+      object => object.clone(),
+    }
+  }
+
+  /// Operations of the content streams of a page, decoded one by one as lopdf would join them
+  /// without the whitespace separating them.
+  fn page_content(doc: &Document, page_id: ObjectId) -> Vec<Operation> {
+    doc
+      .get_page_contents(page_id)
+      .into_iter()
+      .flat_map(|stream_id| {
+        let stream = doc.get_object(stream_id).and_then(Object::as_stream).unwrap();
+        Content::decode(&stream.get_plain_content().unwrap())
+          .unwrap()
+          .operations
+      })
+      .collect()
+  }
+
+  /// Page contents, page resources and optional content of the only page of `doc`.
+  fn page_parts(doc: &Document) -> (Vec<u8>, Object, Object) {
+    let page_id = doc.page_iter().next().unwrap();
+    let content = Content {
+      operations: page_content(doc, page_id),
+    }
+    .encode()
+    .unwrap();
+    let resources = resolve(doc, doc.get_dictionary(page_id).unwrap().get(b"Resources").unwrap());
+    let properties = resolve(doc, doc.catalog().unwrap().get(b"OCProperties").unwrap());
+    (content, resources, properties)
+  }
+
+  #[test]
+  fn strip_restores_the_original() {
+    let original = sample_document();
+    let (content, resources, properties) = page_parts(&original);
+    let wrapped = Content {
+      operations: [Operation::new("q", vec![])]
+        .into_iter()
+        .chain(page_content(&original, original.page_iter().next().unwrap()))
+        .chain([Operation::new("Q", vec![])])
+        .collect::<Vec<_>>(),
+    }
+    .encode()
+    .unwrap();
+
+    let cases = [
+      ("", &wrapped),
+      ("&reuse_tiles=true", &wrapped),
+      ("&layer=background", &content),
+      ("&trace_id=alice&visibility=print_only", &wrapped),
+    ];
+    for (parameters, expected_content) in cases {
+      let upload = MarkUpload {
+        pdf: Arc::new(TempFile::new(&std::env::temp_dir())),
+        image: None,
+        password: None,
+      };
+      sample_document().save(upload.pdf.path()).unwrap();
+      let query = format!(
+        "text=Confidential%0A{{page}}&font_size=24&rot_deg=30&permissions=none{}",
+        parameters
+      );
+      let query = serde_urlencoded::from_str::<MarkQuery>(&query).unwrap();
+      let marked = mark_pdf(&upload, &query, &MarkContext::for_tests()).unwrap();
+
+      let mut doc = Document::load(marked.path()).unwrap();
+      assert_ne!(page_parts(&doc).0, content, "{parameters}");
+      let page_ids = doc.page_iter().collect::<Vec<_>>();
+      strip_watermark(&mut doc, &page_ids).unwrap();
+      let (stripped_content, stripped_resources, stripped_properties) = page_parts(&doc);
+      assert_eq!(&stripped_content, expected_content, "{parameters}");
+      assert_eq!(stripped_resources, resources, "{parameters}");
+      assert_eq!(stripped_properties, properties, "{parameters}");
+    }
+  }
+}
@@ -0,0 +1,159 @@
+use std::fmt::Write;
+
+/// Watermark text with `{variable}` placeholders, expanded separately for every page.
+///
+/// Supported variables are `{page}`, `{pages}`, `{date}`, `{datetime}`, `{request_id}` and `{user}`.
+/// `{{` and `}}` produce literal braces.
+#[derive(Debug, Clone)]
+pub struct TextTemplate {
+  segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+  Literal(String),
+  Variable(Variable),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+  Page,
+  Pages,
+  Date,
+  Datetime,
+  RequestId,
+  User,
+}
+
+/// Values of the template variables that are the same for every page.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+  pub date: String,
+  pub datetime: String,
+  pub request_id: String,
+  pub user: String,
+}
+
+impl TextTemplate {
+  pub fn parse(text: &str) -> Result<Self, String> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+      match c {
+        '{' if chars.as_str().starts_with('{') => {
+          chars.next();
+          literal.push('{');
+        }
+        '}' if chars.as_str().starts_with('}') => {
+          chars.next();
+          literal.push('}');
+        }
+        '{' => {
+          let rest = chars.as_str();
+          let end = rest.find('}').ok_or_else(|| "unclosed `{` in text".to_owned())?;
+          let variable = match &rest[..end] {
+            "page" => Variable::Page,
+            "pages" => Variable::Pages,
+            "date" => Variable::Date,
+            "datetime" => Variable::Datetime,
+            "request_id" => Variable::RequestId,
+            "user" => Variable::User,
+            name => return Err(format!("unknown variable `{{{}}}` in text", name)),
+          };
+          chars = rest[end + 1..].chars();
+          if !literal.is_empty() {
+            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+          }
+          segments.push(Segment::Variable(variable));
+        }
+        '}' => return Err("unmatched `}` in text, use `}}` for a literal brace".to_owned()),
+        c => literal.push(c),
+      }
+    }
+    if !literal.is_empty() {
+      segments.push(Segment::Literal(literal));
+    }
+    Ok(Self { segments })
+  }
+
+  /// Whether the text differs between users.
+  pub fn uses_user(&self) -> bool {
+    self
+      .segments
+      .iter()
+      .any(|segment| matches!(segment, Segment::Variable(Variable::User)))
+  }
+
+  /// Expands the template for the 1-based `page` out of `pages`.
+  pub fn render(&self, vars: &TemplateVars, page: usize, pages: usize) -> String {
+    let mut text = String::new();
+    for segment in &self.segments {
+      let _ = match segment {
+        Segment::Literal(literal) => text.write_str(literal),
+        Segment::Variable(Variable::Page) => write!(text, "{}", page),
+        Segment::Variable(Variable::Pages) => write!(text, "{}", pages),
+        Segment::Variable(Variable::Date) => text.write_str(&vars.date),
+        Segment::Variable(Variable::Datetime) => text.write_str(&vars.datetime),
+        Segment::Variable(Variable::RequestId) => text.write_str(&vars.request_id),
+        Segment::Variable(Variable::User) => text.write_str(&vars.user),
+      };
+    }
+    text
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn vars() -> TemplateVars {
+    TemplateVars {
+      date: "2024-05-01".to_owned(),
+      datetime: "2024-05-01 08:00:00 CST".to_owned(),
+      request_id: "01HWX".to_owned(),
+      user: "Alice".to_owned(),
+    }
+  }
+
+  fn render(text: &str) -> String {
+    TextTemplate::parse(text).unwrap().render(&vars(), 3, 12)
+  }
+
+  #[test]
+  fn variables() {
+    assert_eq!(render("Page {page} of {pages}"), "Page 3 of 12");
+    assert_eq!(render("{user} {date}"), "Alice 2024-05-01");
+    assert_eq!(render("{datetime}/{request_id}"), "2024-05-01 08:00:00 CST/01HWX");
+    assert_eq!(render("{page}{page}"), "33");
+  }
+
+  #[test]
+  fn literal_text() {
+    assert_eq!(render("CONFIDENTIAL"), "CONFIDENTIAL");
+    assert_eq!(render(""), "");
+    assert_eq!(render("line 1\nline 2 {page}"), "line 1\nline 2 3");
+  }
+
+  #[test]
+  fn escaped_braces() {
+    assert_eq!(render("{{page}}"), "{page}");
+    assert_eq!(render("{{{page}}}"), "{3}");
+    assert_eq!(render("a }} b {{"), "a } b {");
+  }
+
+  #[test]
+  fn user_variable() {
+    let uses_user = |text| TextTemplate::parse(text).unwrap().uses_user();
+    assert!(uses_user("Copy of {user}"));
+    assert!(!uses_user("Page {page} of {pages}"));
+    assert!(!uses_user("{{user}}"));
+  }
+
+  #[test]
+  fn invalid() {
+    for text in ["{unknown}", "{Page}", "{}", "{page", "page}", "{ page }", "}{"] {
+      assert!(TextTemplate::parse(text).is_err(), "{:?} should be rejected", text);
+    }
+  }
+}
@@ -0,0 +1,183 @@
+use axum::body::Bytes;
+use std::io::Read;
+
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const VERSION: u16 = 20;
+/// General purpose flag marking file names as UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+/// 1980-01-01 00:00 in MS-DOS format, so that archives of the same files are identical.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// CRC-32 and size of the contents of an entry, which its header needs up front.
+pub fn checksum(mut reader: impl Read) -> std::io::Result<(u32, u64)> {
+  let mut hasher = crc32fast::Hasher::new();
+  let mut buffer = vec![0; 64 * 1024];
+  let mut size = 0;
+  loop {
+    let read = reader.read(&mut buffer)?;
+    if read == 0 {
+      return Ok((hasher.finalize(), size));
+    }
+    hasher.update(&buffer[..read]);
+    size += read as u64;
+  }
+}
+
+/// Writes a ZIP archive of uncompressed entries (PDFs hardly compress any further) piece by piece,
+/// so that it can be sent while later entries are still being produced. Each entry is its header
+/// followed by exactly the announced number of bytes, written by the caller.
+#[derive(Default)]
+pub struct ZipWriter {
+  central_directory: Vec<u8>,
+  offset: u64,
+  entries: usize,
+}
+
+impl ZipWriter {
+  /// The header of the next entry, to be followed by its `size` bytes of contents.
+  pub fn entry(&mut self, name: &str, crc: u32, size: u64) -> anyhow::Result<Bytes> {
+    let offset = u32::try_from(self.offset)?;
+    let size_u32 = u32::try_from(size)?;
+    let name_len = u16::try_from(name.len())?;
+
+    let mut header = Vec::with_capacity(30 + name.len());
+    header.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes()); // stored
+    header.extend_from_slice(&DOS_TIME.to_le_bytes());
+    header.extend_from_slice(&DOS_DATE.to_le_bytes());
+    header.extend_from_slice(&crc.to_le_bytes());
+    header.extend_from_slice(&size_u32.to_le_bytes()); // compressed
+    header.extend_from_slice(&size_u32.to_le_bytes()); // uncompressed
+    header.extend_from_slice(&name_len.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes()); // extra field
+    header.extend_from_slice(name.as_bytes());
+
+    let directory = &mut self.central_directory;
+    directory.extend_from_slice(&CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+    directory.extend_from_slice(&VERSION.to_le_bytes()); // made by
+    directory.extend_from_slice(&header[4..30]);
+    directory.extend_from_slice(&0u16.to_le_bytes()); // comment
+    directory.extend_from_slice(&0u16.to_le_bytes()); // disk
+    directory.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+    directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+    directory.extend_from_slice(&offset.to_le_bytes());
+    directory.extend_from_slice(name.as_bytes());
+
+    self.offset += header.len() as u64 + size;
+    self.entries += 1;
+    Ok(header.into())
+  }
+
+  /// The central directory, ending the archive.
+  pub fn finish(self) -> anyhow::Result<Bytes> {
+    let entries = u16::try_from(self.entries)?;
+    let size = u32::try_from(self.central_directory.len())?;
+    let offset = u32::try_from(self.offset)?;
+
+    let mut end = self.central_directory;
+    end.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // disk
+    end.extend_from_slice(&0u16.to_le_bytes()); // disk of central directory
+    end.extend_from_slice(&entries.to_le_bytes()); // on this disk
+    end.extend_from_slice(&entries.to_le_bytes()); // total
+    end.extend_from_slice(&size.to_le_bytes());
+    end.extend_from_slice(&offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // comment
+    Ok(end.into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn u16_at(data: &[u8], at: usize) -> usize {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap()) as usize
+  }
+
+  fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+  }
+
+  /// Reads the entries of an archive the way unzip does: through the central directory found
+  /// from the end record, checking every local header against it.
+  fn read_archive(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let end = archive.len() - 22;
+    assert_eq!(u32_at(archive, end), END_OF_CENTRAL_DIRECTORY);
+    let entries = u16_at(archive, end + 10);
+    let directory_size = u32_at(archive, end + 12) as usize;
+    let mut at = u32_at(archive, end + 16) as usize;
+    assert_eq!(at + directory_size, end);
+
+    let mut files = Vec::new();
+    for _ in 0..entries {
+      assert_eq!(u32_at(archive, at), CENTRAL_DIRECTORY_HEADER);
+      assert_eq!(u16_at(archive, at + 10), 0, "entries are stored");
+      let crc = u32_at(archive, at + 16);
+      let size = u32_at(archive, at + 20) as usize;
+      assert_eq!(u32_at(archive, at + 24) as usize, size);
+      let name_len = u16_at(archive, at + 28);
+      let extra_len = u16_at(archive, at + 30);
+      let comment_len = u16_at(archive, at + 32);
+      let offset = u32_at(archive, at + 42) as usize;
+      let name = &archive[at + 46..at + 46 + name_len];
+
+      assert_eq!(u32_at(archive, offset), LOCAL_FILE_HEADER);
+      assert_eq!(archive[offset + 4..offset + 30], archive[at + 6..at + 32]);
+      let data_start = offset + 30 + u16_at(archive, offset + 26) + u16_at(archive, offset + 28);
+      let data = &archive[data_start..data_start + size];
+      assert_eq!(crc32fast::hash(data), crc);
+
+      files.push((String::from_utf8(name.to_vec()).unwrap(), data.to_vec()));
+      at += 46 + name_len + extra_len + comment_len;
+    }
+    assert_eq!(at, end);
+    files
+  }
+
+  fn write_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipWriter::default();
+    let mut archive = Vec::new();
+    for (name, data) in files {
+      let (crc, size) = checksum(*data).unwrap();
+      archive.extend_from_slice(&zip.entry(name, crc, size).unwrap());
+      archive.extend_from_slice(data);
+    }
+    archive.extend_from_slice(&zip.finish().unwrap());
+    archive
+  }
+
+  #[test]
+  fn round_trip() {
+    let large = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let files: [(&str, &[u8]); 4] = [
+      ("001-report.pdf", b"%PDF-1.7 first"),
+      ("002-empty.pdf", b""),
+      ("003-bericht-über.pdf", b"%PDF-1.7 second"),
+      ("004-large.pdf", &large),
+    ];
+    let archive = write_archive(&files);
+    let read = read_archive(&archive);
+    assert_eq!(read.len(), files.len());
+    for ((name, data), (read_name, read_data)) in files.iter().zip(read) {
+      assert_eq!(*name, read_name);
+      assert_eq!(*data, read_data.as_slice());
+    }
+  }
+
+  #[test]
+  fn empty_archive() {
+    assert!(read_archive(&write_archive(&[])).is_empty());
+  }
+
+  #[test]
+  fn checksums() {
+    assert_eq!(checksum(&b""[..]).unwrap(), (0, 0));
+    assert_eq!(checksum(&b"123456789"[..]).unwrap(), (0xcbf43926, 9));
+  }
+}
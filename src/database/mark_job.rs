@@ -0,0 +1,145 @@
+use chrono::{DateTime, Utc};
+use sqlx::{types::Uuid, PgPool};
+use ulid::Ulid;
+
+use crate::AppState;
+
+pub trait AsMarkJobAccessor {
+  fn mark_job_accessor(&self) -> MarkJobAccessor<'_>;
+}
+
+impl AsMarkJobAccessor for AppState {
+  fn mark_job_accessor(&self) -> MarkJobAccessor<'_> {
+    MarkJobAccessor {
+      database: &self.database,
+    }
+  }
+}
+
+pub struct MarkJobAccessor<'db> {
+  pub database: &'db PgPool,
+}
+
+/// A watermark job, without its result.
+#[derive(Debug, sqlx::FromRow)]
+pub struct MarkJob {
+  pub id: Uuid,
+  /// One of `queued`, `running`, `succeeded` and `failed`.
+  pub status: String,
+  pub pages_done: i32,
+  pub pages_total: Option<i32>,
+  /// Problem type of a failed job.
+  pub error: Option<String>,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+}
+
+impl<'db> MarkJobAccessor<'db> {
+  pub async fn create(&self, id: Ulid, expires_at: DateTime<Utc>) -> Result<MarkJob, sqlx::Error> {
+    sqlx::query_as!(
+      MarkJob,
+      "INSERT INTO mark_job (id, status, pages_done, created_at, updated_at, expires_at)
+      VALUES ($1, 'queued', 0, $2, $2, $3)
+      RETURNING id, status, pages_done, pages_total, error, created_at, updated_at, expires_at",
+      Uuid::from(id),
+      Utc::now(),
+      expires_at
+    )
+    .fetch_one(self.database)
+    .await
+  }
+
+  pub async fn get(&self, id: Ulid) -> Result<Option<MarkJob>, sqlx::Error> {
+    sqlx::query_as!(
+      MarkJob,
+      "SELECT id, status, pages_done, pages_total, error, created_at, updated_at, expires_at
+      FROM mark_job WHERE id = $1 AND (expires_at > $2 OR status IN ('queued', 'running'))",
+      Uuid::from(id),
+      Utc::now()
+    )
+    .fetch_optional(self.database)
+    .await
+  }
+
+  pub async fn set_progress(&self, id: Ulid, pages_done: i32, pages_total: Option<i32>) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+      "UPDATE mark_job SET status = 'running', pages_done = $2, pages_total = $3, updated_at = $4
+      WHERE id = $1",
+      Uuid::from(id),
+      pages_done,
+      pages_total,
+      Utc::now()
+    )
+    .execute(self.database)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn succeed(&self, id: Ulid, result: &[u8], expires_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+      "UPDATE mark_job
+      SET status = 'succeeded', pages_done = COALESCE(pages_total, pages_done), result = $2,
+          updated_at = $3, expires_at = $4
+      WHERE id = $1",
+      Uuid::from(id),
+      result,
+      Utc::now(),
+      expires_at
+    )
+    .execute(self.database)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn fail(&self, id: Ulid, error: &str, expires_at: DateTime<Utc>) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+      "UPDATE mark_job SET status = 'failed', error = $2, updated_at = $3, expires_at = $4
+      WHERE id = $1",
+      Uuid::from(id),
+      error,
+      Utc::now(),
+      expires_at
+    )
+    .execute(self.database)
+    .await?;
+    Ok(())
+  }
+
+  /// Fails the jobs that were still queued or running, returning how many there were.
+  pub async fn fail_unfinished(&self, error: &str, expires_at: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+      "UPDATE mark_job SET status = 'failed', error = $1, updated_at = $2, expires_at = $3
+      WHERE status IN ('queued', 'running')",
+      error,
+      Utc::now(),
+      expires_at
+    )
+    .execute(self.database)
+    .await?;
+    Ok(result.rows_affected())
+  }
+
+  /// The output of a succeeded job.
+  pub async fn get_result(&self, id: Ulid) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    let record = sqlx::query!(
+      "SELECT result FROM mark_job WHERE id = $1 AND status = 'succeeded' AND expires_at > $2",
+      Uuid::from(id),
+      Utc::now()
+    )
+    .fetch_optional(self.database)
+    .await?;
+    Ok(record.and_then(|record| record.result))
+  }
+
+  /// Deletes finished jobs past their expiry, pending jobs are kept however long they wait.
+  pub async fn delete_expired(&self) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+      "DELETE FROM mark_job WHERE expires_at <= $1 AND status IN ('succeeded', 'failed')",
+      Utc::now()
+    )
+    .execute(self.database)
+    .await?;
+    Ok(result.rows_affected())
+  }
+}
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use crate::AppState;
+
+pub trait AsMarkPresetAccessor {
+  fn mark_preset_accessor(&self) -> MarkPresetAccessor<'_>;
+}
+
+impl AsMarkPresetAccessor for AppState {
+  fn mark_preset_accessor(&self) -> MarkPresetAccessor<'_> {
+    MarkPresetAccessor {
+      database: &self.database,
+    }
+  }
+}
+
+pub struct MarkPresetAccessor<'db> {
+  pub database: &'db PgPool,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct MarkPreset {
+  pub name: String,
+  pub description: String,
+  /// Watermark parameters as a URL encoded query string.
+  pub parameters: String,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+impl<'db> MarkPresetAccessor<'db> {
+  pub async fn list(&self) -> Result<Vec<MarkPreset>, sqlx::Error> {
+    sqlx::query_as!(MarkPreset, "SELECT * FROM mark_preset ORDER BY name")
+      .fetch_all(self.database)
+      .await
+  }
+
+  pub async fn get(&self, name: &str) -> Result<Option<MarkPreset>, sqlx::Error> {
+    sqlx::query_as!(MarkPreset, "SELECT * FROM mark_preset WHERE name = $1", name)
+      .fetch_optional(self.database)
+      .await
+  }
+
+  /// Creates a preset, `None` if a preset of the same name exists.
+  pub async fn create(
+    &self,
+    name: &str,
+    description: &str,
+    parameters: &str,
+  ) -> Result<Option<MarkPreset>, sqlx::Error> {
+    sqlx::query_as!(
+      MarkPreset,
+      "INSERT INTO mark_preset (name, description, parameters, created_at, updated_at)
+      VALUES ($1, $2, $3, $4, $4)
+      ON CONFLICT (name) DO NOTHING RETURNING *",
+      name,
+      description,
+      parameters,
+      Utc::now()
+    )
+    .fetch_optional(self.database)
+    .await
+  }
+
+  pub async fn update(
+    &self,
+    name: &str,
+    description: &str,
+    parameters: &str,
+  ) -> Result<Option<MarkPreset>, sqlx::Error> {
+    sqlx::query_as!(
+      MarkPreset,
+      "UPDATE mark_preset SET description = $2, parameters = $3, updated_at = $4
+      WHERE name = $1 RETURNING *",
+      name,
+      description,
+      parameters,
+      Utc::now()
+    )
+    .fetch_optional(self.database)
+    .await
+  }
+
+  pub async fn delete(&self, name: &str) -> Result<Option<MarkPreset>, sqlx::Error> {
+    sqlx::query_as!(MarkPreset, "DELETE FROM mark_preset WHERE name = $1 RETURNING *", name)
+      .fetch_optional(self.database)
+      .await
+  }
+}
@@ -1,2 +1,6 @@
 mod event;
+mod mark_job;
+mod mark_preset;
 pub use event::*;
+pub use mark_job::*;
+pub use mark_preset::*;
@@ -44,12 +44,17 @@ struct ProblemDetails {
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
   fn into_response(self) -> Response<Body> {
-    if let AppError::Unknown(err) = &self {
-      error!("Internal error: {:?}", err);
+    match &self {
+      AppError::Unknown(err) => error!("Internal error: {:?}", err),
+      AppError::DomainError(DomainError::PdfInternalError { cause }) => error!("Internal PDF error: {}", cause),
+      _ => {}
     }
 
     let mut headers = HeaderMap::new();
     headers.insert("Content-Type", "application/problem+json".parse().unwrap());
+    if let AppError::DomainError(DomainError::PdfBusy { retry_after_secs }) = &self {
+      headers.insert("Retry-After", retry_after_secs.to_string().parse().unwrap());
+    }
 
     let code = match &self {
       Self::Unknown(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -120,8 +125,26 @@ domain_errors! {
         "The document could not be processed due to an internal error.";
     PdfFormatError, "utils.mark.format_error", StatusCode::BAD_REQUEST,
         "The document could not be loaded due to a format parsing error.";
+    PdfPasswordError, "utils.mark.password_error", StatusCode::FORBIDDEN,
+        "The document is encrypted and the supplied password is missing or incorrect.";
+    PdfImageFormatError, "utils.mark.image_format_error", StatusCode::BAD_REQUEST,
+        "The watermark image could not be loaded, only PNG and JPEG are supported.";
+    PdfInvalidParameter { name: &'static str }, "utils.mark.invalid_parameter", StatusCode::BAD_REQUEST,
+        "The watermark parameters are invalid.";
+    PdfTooLarge, "utils.mark.too_large", StatusCode::PAYLOAD_TOO_LARGE,
+        "The document exceeds the maximum upload size.";
+    PdfBusy { retry_after_secs: u64 }, "utils.mark.busy", StatusCode::SERVICE_UNAVAILABLE,
+        "Too many documents are being watermarked, please retry later.";
     PdfTimeout, "utils.mark.timeout", StatusCode::PAYLOAD_TOO_LARGE,
         "The document has exceeded the processing timeout.";
+    MarkJobNotFound { id: Ulid }, "utils.mark.job_not_found", StatusCode::NOT_FOUND,
+        "The requested watermark job could not be found or has expired.";
+    MarkJobNotReady { id: Ulid }, "utils.mark.job_not_ready", StatusCode::CONFLICT,
+        "The watermark job has not finished yet or has failed.";
+    MarkPresetNotFound { name: String }, "utils.mark.preset_not_found", StatusCode::NOT_FOUND,
+        "The requested watermark preset could not be found.";
+    MarkPresetExists { name: String }, "utils.mark.preset_exists", StatusCode::CONFLICT,
+        "A watermark preset of the same name already exists.";
     EventNotFound { id: Ulid }, "events.not_found", StatusCode::NOT_FOUND,
         "The requested event could not be found.";
 }
@@ -2,6 +2,7 @@ use axum::extract::DefaultBodyLimit;
 use axum::routing::*;
 use axum::Router;
 use sqlx::{MySqlPool, PgPool};
+use std::sync::Arc;
 use tracing::info;
 
 mod controllers;
@@ -16,6 +17,19 @@ use utoipa_scalar::Servable;
 
 #[derive(OpenApi)]
 #[openapi(paths(
+  controllers::utils::mark,
+  controllers::utils::mark_batch,
+  controllers::utils::find_trace,
+  controllers::utils::inspect,
+  controllers::utils::mark_status,
+  controllers::utils::create_job,
+  controllers::utils::get_job,
+  controllers::utils::get_job_result,
+  controllers::utils::list_presets,
+  controllers::utils::get_preset,
+  controllers::utils::create_preset,
+  controllers::utils::update_preset,
+  controllers::utils::delete_preset,
   controllers::events::list,
   controllers::events::get,
   controllers::events::create,
@@ -29,6 +43,8 @@ pub struct AppState {
   pub database: PgPool,
   pub legacy_database: MySqlPool,
   pub settings: settings::Settings,
+  pub watermark_font: Option<Arc<controllers::utils::EmbeddedFont>>,
+  pub mark_pdf_queue: Arc<controllers::utils::JobQueue>,
 }
 
 #[tokio::main]
@@ -37,22 +53,63 @@ async fn main() {
   let postgres = PgPool::connect(&settings.database.url)
     .await
     .expect("failed to connect to postgres");
+  // the migrations are embedded at compile time, and only cover the tables added since the older
+  // ones, which are managed outside of this service
+  sqlx::migrate!()
+    .run(&postgres)
+    .await
+    .expect("failed to run postgres migrations");
   let mysql = MySqlPool::connect(&settings.database.legacy_url)
     .await
     .expect("failed to connect to mysql");
+  controllers::utils::validate_settings(&settings.utils).expect("invalid watermark settings");
+  let watermark_font = settings.utils.mark_pdf_font_path.as_ref().map(|path| {
+    let font = controllers::utils::EmbeddedFont::load(path, settings.utils.mark_pdf_font_index)
+      .expect("failed to load watermark font");
+    Arc::new(font)
+  });
   let app_state = AppState {
     database: postgres,
     legacy_database: mysql,
     settings: settings.clone(),
+    watermark_font,
+    mark_pdf_queue: Arc::new(controllers::utils::JobQueue::new(&settings.utils)),
   };
 
   tracing_subscriber::fmt::init();
+  controllers::utils::fail_interrupted_jobs(&app_state)
+    .await
+    .expect("failed to mark interrupted jobs as failed");
 
   let app = Router::new()
     .route(
       "/utils/mark",
       post(controllers::utils::mark).layer(DefaultBodyLimit::max(settings.utils.mark_pdf_max_size_byte)),
     )
+    .route(
+      "/utils/mark/batch",
+      post(controllers::utils::mark_batch).layer(DefaultBodyLimit::max(settings.utils.mark_pdf_max_size_byte)),
+    )
+    .route(
+      "/utils/mark/inspect",
+      post(controllers::utils::inspect).layer(DefaultBodyLimit::max(settings.utils.mark_pdf_max_size_byte)),
+    )
+    .route(
+      "/utils/mark/trace",
+      post(controllers::utils::find_trace).layer(DefaultBodyLimit::max(settings.utils.mark_pdf_max_size_byte)),
+    )
+    .route(
+      "/utils/mark/jobs",
+      post(controllers::utils::create_job).layer(DefaultBodyLimit::max(settings.utils.mark_pdf_max_size_byte)),
+    )
+    .route("/utils/mark/jobs/:id", get(controllers::utils::get_job))
+    .route("/utils/mark/jobs/:id/result", get(controllers::utils::get_job_result))
+    .route("/utils/mark/status", get(controllers::utils::mark_status))
+    .route("/utils/mark/presets", get(controllers::utils::list_presets))
+    .route("/utils/mark/presets", post(controllers::utils::create_preset))
+    .route("/utils/mark/presets/:name", get(controllers::utils::get_preset))
+    .route("/utils/mark/presets/:name", put(controllers::utils::update_preset))
+    .route("/utils/mark/presets/:name", delete(controllers::utils::delete_preset))
     .route("/events", get(controllers::events::list))
     .route("/events", post(controllers::events::create))
     .route("/events/:id", get(controllers::events::get))
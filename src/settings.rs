@@ -5,6 +5,21 @@ use std::env;
 #[derive(Debug, Deserialize, Clone)]
 pub struct UtilsSettings {
   pub mark_pdf_max_size_byte: usize,
+  pub mark_pdf_timeout_secs: u64,
+  pub mark_pdf_font_path: Option<String>,
+  #[serde(default)]
+  pub mark_pdf_font_index: u32,
+  pub mark_pdf_timezone: String,
+  pub mark_pdf_date_format: String,
+  pub mark_pdf_datetime_format: String,
+  pub mark_pdf_owner_password: Option<String>,
+  pub mark_pdf_temp_dir: Option<String>,
+  pub mark_pdf_max_jobs: usize,
+  pub mark_pdf_max_queue: usize,
+  pub mark_pdf_batch_max_jobs: usize,
+  pub mark_pdf_retry_after_secs: u64,
+  pub mark_pdf_job_timeout_secs: u64,
+  pub mark_pdf_job_ttl_secs: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]